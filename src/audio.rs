@@ -0,0 +1,1271 @@
+use actix_web::web::Bytes;
+use std::borrow::Cow;
+use std::io::Cursor;
+use symphonia::core::audio::{AudioBuffer, Signal};
+use symphonia::core::codecs::DecoderOptions;
+
+/// Mono audio. Sources with multiple channels are mixed down when decoding.
+pub struct DecodedAudio {
+    pub samples: Vec<f32>,
+    /// Set when effects made the audio stereo. `samples` is the left channel then.
+    pub right_channel: Option<Vec<f32>>,
+    pub sample_rate: u32,
+}
+
+/// The container was recognized, but there is no decoder for the codec of the audio in it.
+#[derive(derive_more::Display, derive_more::Error, Debug)]
+#[display("no decoder for the {codec} codec")]
+pub struct UnsupportedCodec {
+    #[error(not(source))]
+    pub codec: String,
+}
+
+fn codec_name(codec: symphonia::core::codecs::CodecType) -> String {
+    use symphonia::core::codecs::*;
+    match codec {
+        CODEC_TYPE_OPUS => "Opus".to_string(),
+        CODEC_TYPE_AAC => "AAC".to_string(),
+        CODEC_TYPE_ALAC => "ALAC".to_string(),
+        CODEC_TYPE_WAVPACK => "WavPack".to_string(),
+        _ => codec.to_string(),
+    }
+}
+
+/// Decodes the audio and mixes all channels down to one.
+pub fn decode(audio_file: Bytes) -> anyhow::Result<DecodedAudio> {
+    let (channels, sample_rate) = decode_channels(audio_file)?;
+    let mut samples = channels.first().cloned().unwrap_or_default();
+    if channels.len() > 1 {
+        for channel in &channels[1..] {
+            for (sample, other) in samples.iter_mut().zip(channel) {
+                *sample += other;
+            }
+        }
+        for sample in samples.iter_mut() {
+            *sample /= channels.len() as f32;
+        }
+    }
+    Ok(DecodedAudio {
+        samples,
+        right_channel: None,
+        sample_rate,
+    })
+}
+
+/// Samples of each channel and the sample rate.
+fn decode_channels(audio_file: Bytes) -> anyhow::Result<(Vec<Vec<f32>>, u32)> {
+    let mss = symphonia::core::io::MediaSourceStream::new(
+        Box::new(Cursor::new(audio_file)),
+        Default::default(),
+    );
+    let probe = symphonia::default::get_probe().format(
+        &Default::default(),
+        mss,
+        &Default::default(),
+        &Default::default(),
+    )?;
+    let mut format = probe.format;
+    let track = &format
+        .tracks()
+        .first()
+        .ok_or(symphonia::core::errors::Error::DecodeError("no track"))?;
+    let sample_rate =
+        track
+            .codec_params
+            .sample_rate
+            .ok_or(symphonia::core::errors::Error::DecodeError(
+                "no sample rate",
+            ))?;
+
+    // Create a decoder for the audio track.
+    let codecs = symphonia::default::get_codecs();
+    if codecs.get_codec(track.codec_params.codec).is_none() {
+        return Err(UnsupportedCodec {
+            codec: codec_name(track.codec_params.codec),
+        }
+        .into());
+    }
+    let mut decoder = codecs.make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut channels: Vec<Vec<f32>> = Vec::new();
+
+    // Decode and process the audio packets.
+    while let Ok(packet) = format.next_packet() {
+        // Decode the packet into audio frames.
+        if let Ok(decoded) = decoder.decode(&packet) {
+            let mut converted = AudioBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+            decoded.convert(&mut converted);
+            let count = converted.spec().channels.count();
+            channels.resize_with(channels.len().max(count), Vec::new);
+            for (channel, samples) in channels.iter_mut().enumerate().take(count) {
+                samples.extend(converted.chan(channel));
+            }
+        }
+    }
+
+    Ok((channels, sample_rate))
+}
+
+/// Processing applied to the decoded audio before it is encoded again.
+#[derive(serde::Serialize, Debug, Hash, PartialEq, Eq, Clone)]
+pub struct Effects {
+    pub volume_factor: ordered_float::NotNan<f32>,
+    /// Scale the audio so that its highest peak reaches this level. Replaces the volume factor.
+    pub target_peak_dbfs: Option<ordered_float::NotNan<f32>>,
+    /// Trim the audio to zero crossings so that it can be looped without clicks.
+    pub loop_ready: bool,
+    /// Offsets at which short beeps are mixed in, e.g. for measuring playback latency.
+    pub beep_markers_ms: Vec<u32>,
+    /// Pseudo-stereo width from 0 (identical channels) to 1. Requires stereo output.
+    pub stereo_width: ordered_float::NotNan<f32>,
+    /// Cutoff frequency below which the audio is attenuated, e.g. to remove rumble.
+    pub highpass_hz: Option<ordered_float::NotNan<f32>>,
+    /// Cutoff frequency above which the audio is attenuated, e.g. for telephony-style output.
+    pub lowpass_hz: Option<ordered_float::NotNan<f32>>,
+    /// Keeps peaks below a threshold after the gain is applied, instead of letting them clip.
+    pub limiter: Option<LimiterSettings>,
+}
+
+#[derive(serde::Serialize, Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct LimiterSettings {
+    pub threshold_dbfs: ordered_float::NotNan<f32>,
+    /// How long before a peak the gain starts to go down.
+    pub lookahead_ms: u32,
+    /// How long it takes for the gain to recover after a peak.
+    pub release_ms: u32,
+}
+
+impl LimiterSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(-30.0..=0.0).contains(&self.threshold_dbfs.into_inner()) {
+            anyhow::bail!("limiter threshold must be between -30 and 0 dBFS");
+        }
+        if self.lookahead_ms == 0 || self.release_ms == 0 {
+            anyhow::bail!("limiter look-ahead and release must be positive");
+        }
+        Ok(())
+    }
+}
+
+impl Default for Effects {
+    fn default() -> Self {
+        Self {
+            volume_factor: ordered_float::NotNan::new(1.0).unwrap(),
+            target_peak_dbfs: None,
+            loop_ready: false,
+            beep_markers_ms: Vec::new(),
+            stereo_width: ordered_float::NotNan::new(0.0).unwrap(),
+            highpass_hz: None,
+            lowpass_hz: None,
+            limiter: None,
+        }
+    }
+}
+
+/// The requested effects cannot be applied to the decoded audio.
+#[derive(derive_more::Display, derive_more::Error, Debug)]
+#[display("{_0}")]
+pub struct InvalidEffects(#[error(not(source))] pub String);
+
+impl Effects {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        const MAX_BEEP_MARKERS: usize = 32;
+
+        if self.beep_markers_ms.len() > MAX_BEEP_MARKERS {
+            anyhow::bail!("at most {} beep markers are allowed", MAX_BEEP_MARKERS);
+        }
+        if let Some(target_peak_dbfs) = self.target_peak_dbfs {
+            if self.volume_factor != 1.0 {
+                anyhow::bail!("volume and target_peak_dbfs cannot be combined");
+            }
+            if !(-60.0..=0.0).contains(&target_peak_dbfs.into_inner()) {
+                anyhow::bail!("target_peak_dbfs must be between -60 and 0");
+            }
+        }
+        if !(0.0..=1.0).contains(&self.stereo_width.into_inner()) {
+            anyhow::bail!("stereo_width must be between 0 and 1");
+        }
+        for (name, cutoff_hz) in [
+            ("highpass_hz", self.highpass_hz),
+            ("lowpass_hz", self.lowpass_hz),
+        ] {
+            if cutoff_hz.is_some_and(|cutoff_hz| cutoff_hz.into_inner() <= 0.0) {
+                anyhow::bail!("{} must be positive", name);
+            }
+        }
+        if let (Some(highpass_hz), Some(lowpass_hz)) = (self.highpass_hz, self.lowpass_hz) {
+            if highpass_hz >= lowpass_hz {
+                anyhow::bail!("highpass_hz must be below lowpass_hz");
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the filter cutoffs before any audio is available, for the highest sample rate the
+    /// audio can have.
+    pub fn validate_cutoffs(&self, sample_rate: u32) -> Result<(), InvalidEffects> {
+        for (kind, cutoff_hz) in [
+            (FilterKind::HighPass, self.highpass_hz),
+            (FilterKind::LowPass, self.lowpass_hz),
+        ] {
+            if let Some(cutoff_hz) = cutoff_hz {
+                Biquad::new(kind, cutoff_hz.into_inner(), sample_rate)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Human readable steps in the order they are applied by [`apply_effects`].
+    pub fn describe(&self) -> Vec<String> {
+        let mut steps = Vec::new();
+        if let Some(highpass_hz) = self.highpass_hz {
+            steps.push(format!("high-pass at {} Hz", highpass_hz));
+        }
+        if let Some(lowpass_hz) = self.lowpass_hz {
+            steps.push(format!("low-pass at {} Hz", lowpass_hz));
+        }
+        match self.target_peak_dbfs {
+            Some(target_peak_dbfs) => {
+                steps.push(format!("normalize peak to {} dBFS", target_peak_dbfs))
+            }
+            None if self.volume_factor != 1.0 => {
+                steps.push(format!("gain x{}", self.volume_factor))
+            }
+            None => {}
+        }
+        if self.loop_ready {
+            steps.push("trim to zero crossings".to_string());
+        }
+        for offset_ms in &self.beep_markers_ms {
+            steps.push(format!("beep at {} ms", offset_ms));
+        }
+        if let Some(limiter) = &self.limiter {
+            steps.push(format!("limit peaks to {} dBFS", limiter.threshold_dbfs));
+        }
+        if self.stereo_width != 0.0 {
+            steps.push(format!("stereo width {}", self.stereo_width));
+        }
+        steps
+    }
+}
+
+pub fn apply_effects(audio: &mut DecodedAudio, effects: &Effects) -> anyhow::Result<()> {
+    if let Some(highpass_hz) = effects.highpass_hz {
+        let filter = Biquad::new(
+            FilterKind::HighPass,
+            highpass_hz.into_inner(),
+            audio.sample_rate,
+        )?;
+        filter.apply(&mut audio.samples);
+    }
+    if let Some(lowpass_hz) = effects.lowpass_hz {
+        let filter = Biquad::new(
+            FilterKind::LowPass,
+            lowpass_hz.into_inner(),
+            audio.sample_rate,
+        )?;
+        filter.apply(&mut audio.samples);
+    }
+    let gain = match effects.target_peak_dbfs {
+        Some(target_peak_dbfs) => {
+            let peak = peak_amplitude(&audio.samples);
+            if peak > 0.0 {
+                10.0f32.powf(target_peak_dbfs.into_inner() / 20.0) / peak
+            } else {
+                1.0
+            }
+        }
+        None => effects.volume_factor.into_inner(),
+    };
+    for sample in audio.samples.iter_mut() {
+        *sample *= gain;
+    }
+    if effects.loop_ready {
+        trim_to_zero_crossings(&mut audio.samples);
+    }
+    for &offset_ms in &effects.beep_markers_ms {
+        add_beep(audio, offset_ms)?;
+    }
+    if let Some(limiter) = &effects.limiter {
+        limit_peaks(audio, limiter);
+    }
+    if effects.stereo_width != 0.0 {
+        widen_stereo(audio, effects.stereo_width.into_inner());
+    }
+    Ok(())
+}
+
+enum FilterKind {
+    HighPass,
+    LowPass,
+}
+
+/// Second order Butterworth filter, using the coefficients from the Audio EQ Cookbook.
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Biquad {
+    fn new(kind: FilterKind, cutoff_hz: f32, sample_rate: u32) -> Result<Self, InvalidEffects> {
+        let nyquist_hz = sample_rate as f32 / 2.0;
+        if cutoff_hz >= nyquist_hz {
+            return Err(InvalidEffects(format!(
+                "filter cutoff of {} Hz must be below {} Hz for this audio",
+                cutoff_hz, nyquist_hz
+            )));
+        }
+        let omega = std::f32::consts::TAU * cutoff_hz / sample_rate as f32;
+        let alpha = omega.sin() / std::f32::consts::SQRT_2;
+        let cos_omega = omega.cos();
+        let a0 = 1.0 + alpha;
+        let (b0, b1) = match kind {
+            FilterKind::HighPass => ((1.0 + cos_omega) / 2.0, -(1.0 + cos_omega)),
+            FilterKind::LowPass => ((1.0 - cos_omega) / 2.0, 1.0 - cos_omega),
+        };
+        Ok(Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b0 / a0,
+            a1: -2.0 * cos_omega / a0,
+            a2: (1.0 - alpha) / a0,
+        })
+    }
+
+    fn apply(&self, samples: &mut [f32]) {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        for sample in samples.iter_mut() {
+            let x0 = *sample;
+            let y0 = self.b0 * x0 + self.b1 * x1 + self.b2 * x2 - self.a1 * y1 - self.a2 * y2;
+            (x2, x1) = (x1, x0);
+            (y2, y1) = (y1, y0);
+            *sample = y0;
+        }
+    }
+}
+
+/// The decoded audio is longer than the server allows.
+#[derive(derive_more::Display, derive_more::Error, Debug)]
+#[display("audio is {duration_ms} ms long, at most {max_duration_ms} ms are allowed")]
+pub struct AudioTooLong {
+    pub duration_ms: u64,
+    pub max_duration_ms: u64,
+}
+
+/// Makes sure that the audio is at most `max_duration_ms` long, either by cutting off the rest
+/// or by failing.
+pub fn limit_duration(
+    audio: &mut DecodedAudio,
+    max_duration_ms: u64,
+    truncate: bool,
+) -> Result<(), AudioTooLong> {
+    let sample_rate = audio.sample_rate as u64;
+    let max_samples = max_duration_ms * sample_rate / 1000;
+    if audio.samples.len() as u64 <= max_samples {
+        return Ok(());
+    }
+    if !truncate {
+        return Err(AudioTooLong {
+            duration_ms: audio.samples.len() as u64 * 1000 / sample_rate,
+            max_duration_ms,
+        });
+    }
+    audio.samples.truncate(max_samples as usize);
+    if let Some(right_channel) = &mut audio.right_channel {
+        right_channel.truncate(max_samples as usize);
+    }
+    Ok(())
+}
+
+/// Look-ahead limiter that lowers the gain smoothly around peaks above the threshold. The gain
+/// never lets a sample exceed the threshold, so there is no clipping.
+fn limit_peaks(audio: &mut DecodedAudio, settings: &LimiterSettings) {
+    let threshold = 10.0f32.powf(settings.threshold_dbfs.into_inner() / 20.0);
+    let samples_per_ms = audio.sample_rate as f32 / 1000.0;
+    let attack_step = 1.0 / (settings.lookahead_ms as f32 * samples_per_ms).max(1.0);
+    let release_step = 1.0 / (settings.release_ms as f32 * samples_per_ms).max(1.0);
+
+    let mut gains: Vec<f32> = audio
+        .samples
+        .iter()
+        .map(|sample| (threshold / sample.abs()).min(1.0))
+        .collect();
+    // Start lowering the gain ahead of each peak.
+    for i in (0..gains.len().saturating_sub(1)).rev() {
+        gains[i] = gains[i].min(gains[i + 1] + attack_step);
+    }
+    // Let the gain recover slowly after each peak.
+    for i in 1..gains.len() {
+        gains[i] = gains[i].min(gains[i - 1] + release_step);
+    }
+    for (sample, gain) in audio.samples.iter_mut().zip(gains) {
+        *sample *= gain;
+    }
+}
+
+/// Creates a right channel that blends in a slightly delayed copy of the audio (Haas effect),
+/// which is perceived as spatial width instead of an echo.
+fn widen_stereo(audio: &mut DecodedAudio, width: f32) {
+    const DELAY_MS: usize = 15;
+
+    let delay = DELAY_MS * audio.sample_rate as usize / 1000;
+    let right_channel = (0..audio.samples.len())
+        .map(|i| {
+            let delayed = if i >= delay {
+                audio.samples[i - delay]
+            } else {
+                0.0
+            };
+            audio.samples[i] * (1.0 - width) + delayed * width
+        })
+        .collect();
+    audio.right_channel = Some(right_channel);
+}
+
+fn add_beep(audio: &mut DecodedAudio, offset_ms: u32) -> Result<(), InvalidEffects> {
+    const FREQUENCY: f32 = 1000.0;
+    const DURATION_MS: u64 = 50;
+    const AMPLITUDE: f32 = 0.5;
+
+    let sample_rate = audio.sample_rate as u64;
+    let start = offset_ms as u64 * sample_rate / 1000;
+    if start >= audio.samples.len() as u64 {
+        return Err(InvalidEffects(format!(
+            "beep marker at {} ms is beyond the end of the audio ({} ms)",
+            offset_ms,
+            audio.samples.len() as u64 * 1000 / sample_rate
+        )));
+    }
+    let start = start as usize;
+    let end = (start + (DURATION_MS * sample_rate / 1000) as usize).min(audio.samples.len());
+    for (i, sample) in audio.samples[start..end].iter_mut().enumerate() {
+        let time = i as f32 / audio.sample_rate as f32;
+        *sample += AMPLITUDE * (std::f32::consts::TAU * FREQUENCY * time).sin();
+    }
+    Ok(())
+}
+
+/// Cuts the audio at its first and last rising zero crossing. When the result is played in a
+/// loop, the wave continues through zero at the seam instead of jumping.
+fn trim_to_zero_crossings(samples: &mut Vec<f32>) {
+    let is_rising_crossing = |i: &usize| samples[*i - 1] < 0.0 && samples[*i] >= 0.0;
+    let Some(start) = (1..samples.len()).find(is_rising_crossing) else {
+        return;
+    };
+    let Some(end) = (start + 1..samples.len()).rev().find(is_rising_crossing) else {
+        return;
+    };
+    samples.truncate(end);
+    samples.drain(..start);
+}
+
+#[derive(serde::Serialize, Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct EncodingSettings {
+    pub bitrate_kbps: u16,
+    /// LAME quality from 0 (best) to 9 (worst).
+    pub quality: u8,
+    /// Resample to this rate instead of keeping the rate of the source.
+    pub sample_rate: Option<u32>,
+    pub channels: u8,
+}
+
+impl EncodingSettings {
+    pub const LOW_BANDWIDTH: EncodingSettings = EncodingSettings {
+        bitrate_kbps: 32,
+        quality: 2,
+        sample_rate: Some(16000),
+        channels: 1,
+    };
+
+    fn mp3_bitrate(&self) -> anyhow::Result<mp3lame_encoder::Bitrate> {
+        use mp3lame_encoder::Bitrate;
+        Ok(match self.bitrate_kbps {
+            8 => Bitrate::Kbps8,
+            16 => Bitrate::Kbps16,
+            24 => Bitrate::Kbps24,
+            32 => Bitrate::Kbps32,
+            40 => Bitrate::Kbps40,
+            48 => Bitrate::Kbps48,
+            64 => Bitrate::Kbps64,
+            80 => Bitrate::Kbps80,
+            96 => Bitrate::Kbps96,
+            112 => Bitrate::Kbps112,
+            128 => Bitrate::Kbps128,
+            160 => Bitrate::Kbps160,
+            192 => Bitrate::Kbps192,
+            224 => Bitrate::Kbps224,
+            256 => Bitrate::Kbps256,
+            320 => Bitrate::Kbps320,
+            _ => anyhow::bail!("Unsupported bitrate: {}", self.bitrate_kbps),
+        })
+    }
+
+    fn mp3_quality(&self) -> anyhow::Result<mp3lame_encoder::Quality> {
+        use mp3lame_encoder::Quality;
+        Ok(match self.quality {
+            0 => Quality::Best,
+            1 => Quality::SecondBest,
+            2 => Quality::NearBest,
+            3 => Quality::VeryNice,
+            4 => Quality::Nice,
+            5 => Quality::Good,
+            6 => Quality::Decent,
+            7 => Quality::Ok,
+            8 => Quality::SecondWorst,
+            9 => Quality::Worst,
+            _ => anyhow::bail!("Unsupported quality: {}", self.quality),
+        })
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        const MP3_SAMPLE_RATES: [u32; 9] =
+            [8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000];
+
+        self.mp3_bitrate()?;
+        self.mp3_quality()?;
+        if let Some(sample_rate) = self.sample_rate {
+            if !MP3_SAMPLE_RATES.contains(&sample_rate) {
+                anyhow::bail!("Unsupported sample rate: {}", sample_rate);
+            }
+        }
+        if !(1..=2).contains(&self.channels) {
+            anyhow::bail!("Unsupported number of channels: {}", self.channels);
+        }
+        Ok(())
+    }
+}
+
+/// LAME could not be initialized, e.g. because memory is exhausted.
+#[derive(derive_more::Display, derive_more::Error, Debug)]
+#[display("MP3 encoder is not available")]
+pub struct EncoderUnavailable;
+
+/// The encoder returned much less data than expected for the amount of input.
+#[derive(derive_more::Display, derive_more::Error, Debug)]
+#[display("MP3 encoder produced {output_bytes} bytes for {input_samples} samples")]
+pub struct TruncatedEncoderOutput {
+    pub input_samples: usize,
+    pub output_bytes: usize,
+}
+
+/// Linear interpolation between neighboring samples. Frequencies that the target rate cannot
+/// represent are filtered out first, so that they do not alias.
+fn resample(samples: &[f32], from_rate: u32, to_rate: u32) -> Cow<'_, [f32]> {
+    if samples.is_empty() || from_rate == to_rate {
+        return Cow::Borrowed(samples);
+    }
+    let mut filtered;
+    let samples = if to_rate < from_rate {
+        filtered = samples.to_vec();
+        let lowpass = Biquad::new(FilterKind::LowPass, to_rate as f32 * 0.45, from_rate)
+            .expect("cutoff is below the Nyquist frequency of the source");
+        // Applied twice for a steeper slope.
+        lowpass.apply(&mut filtered);
+        lowpass.apply(&mut filtered);
+        &filtered
+    } else {
+        samples
+    };
+    let output_len = (samples.len() as u64 * to_rate as u64 / from_rate as u64) as usize;
+    (0..output_len)
+        .map(|i| {
+            let position = i as f64 * from_rate as f64 / to_rate as f64;
+            let index = position as usize;
+            let fraction = (position - index as f64) as f32;
+            let current = samples[index.min(samples.len() - 1)];
+            let next = samples[(index + 1).min(samples.len() - 1)];
+            current + (next - current) * fraction
+        })
+        .collect()
+}
+
+pub fn encode_mp3(audio: &DecodedAudio, settings: &EncodingSettings) -> anyhow::Result<Vec<u8>> {
+    let mut mp3_file = Vec::new();
+    encode_mp3_into(audio, settings, &mut mp3_file)?;
+    Ok(mp3_file)
+}
+
+/// Feeds the audio to the encoder in chunks and writes the MP3 frames as soon as they are
+/// available. The output is the same as with [`encode_mp3`]. Truncated output is only detected
+/// after everything was written, so the caller has to discard the output on errors.
+pub fn encode_mp3_into(
+    audio: &DecodedAudio,
+    settings: &EncodingSettings,
+    output: &mut impl std::io::Write,
+) -> anyhow::Result<()> {
+    encode_mp3_with_builder(mp3lame_encoder::Builder::new, audio, settings, output)
+}
+
+/// Like [`encode_mp3_into`], but LAME is set up with the builder from `new_builder`, which
+/// returns `None` when LAME cannot be initialized.
+fn encode_mp3_with_builder(
+    new_builder: impl FnOnce() -> Option<mp3lame_encoder::Builder>,
+    audio: &DecodedAudio,
+    settings: &EncodingSettings,
+    output: &mut impl std::io::Write,
+) -> anyhow::Result<()> {
+    /// A multiple of the number of samples in an MP3 frame.
+    const CHUNK_SAMPLES: usize = 1152 * 16;
+
+    let sample_rate = settings.sample_rate.unwrap_or(audio.sample_rate);
+    let samples = resample(&audio.samples, audio.sample_rate, sample_rate);
+    let right_channel = audio
+        .right_channel
+        .as_ref()
+        .map(|right_channel| resample(right_channel, audio.sample_rate, sample_rate));
+
+    let mut mp3_encoder = new_builder().ok_or(EncoderUnavailable)?;
+    mp3_encoder
+        .set_num_channels(settings.channels)
+        .map_err(|_| anyhow::anyhow!("set channels"))?;
+    mp3_encoder
+        .set_sample_rate(sample_rate)
+        .map_err(|_| anyhow::anyhow!("set sample rate"))?;
+    mp3_encoder
+        .set_brate(settings.mp3_bitrate()?)
+        .map_err(|_| anyhow::anyhow!("set brate"))?;
+    mp3_encoder
+        .set_quality(settings.mp3_quality()?)
+        .map_err(|_| anyhow::anyhow!("set quality"))?;
+
+    let mut mp3_encoder = mp3_encoder
+        .build()
+        .map_err(|_| anyhow::anyhow!("initialize LAME encoder"))?;
+
+    let mut output_bytes = 0;
+    let mut mp3_out_buffer =
+        Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(CHUNK_SAMPLES));
+    for start in (0..samples.len()).step_by(CHUNK_SAMPLES) {
+        let end = (start + CHUNK_SAMPLES).min(samples.len());
+        let left = &samples[start..end];
+        mp3_out_buffer.clear();
+        let encoded_size = if settings.channels == 2 {
+            let input = mp3lame_encoder::DualPcm {
+                left,
+                right: right_channel
+                    .as_ref()
+                    .map_or(left, |right| &right[start..end]),
+            };
+            mp3_encoder.encode(input, mp3_out_buffer.spare_capacity_mut())
+        } else {
+            let input = mp3lame_encoder::MonoPcm(left);
+            mp3_encoder.encode(input, mp3_out_buffer.spare_capacity_mut())
+        }
+        .map_err(|_| anyhow::anyhow!("encode"))?;
+        unsafe {
+            mp3_out_buffer.set_len(encoded_size);
+        }
+        output.write_all(&mp3_out_buffer)?;
+        output_bytes += encoded_size;
+    }
+
+    mp3_out_buffer.clear();
+    let encoded_size = mp3_encoder
+        .flush::<mp3lame_encoder::FlushNoGap>(mp3_out_buffer.spare_capacity_mut())
+        .map_err(|_| anyhow::anyhow!("flush"))?;
+    unsafe {
+        mp3_out_buffer.set_len(encoded_size);
+    }
+    output.write_all(&mp3_out_buffer)?;
+    output_bytes += encoded_size;
+
+    check_encoder_output(
+        samples.len(),
+        output_bytes,
+        settings.bitrate_kbps,
+        sample_rate,
+    )?;
+    Ok(())
+}
+
+/// Constant bitrate output is close to the expected size, so anything far below indicates that
+/// the encoder failed silently.
+fn check_encoder_output(
+    input_samples: usize,
+    output_bytes: usize,
+    bitrate_kbps: u16,
+    sample_rate: u32,
+) -> Result<(), TruncatedEncoderOutput> {
+    let bitrate_kbps = bitrate_kbps.min(max_mp3_bitrate_kbps(sample_rate));
+    let expected_bytes = input_samples as u64 * bitrate_kbps as u64 * 1000 / 8 / sample_rate as u64;
+    if input_samples > 0 && (output_bytes == 0 || (output_bytes as u64) < expected_bytes / 4) {
+        return Err(TruncatedEncoderOutput {
+            input_samples,
+            output_bytes,
+        });
+    }
+    Ok(())
+}
+
+/// LAME lowers higher bitrates to the maximum of the MPEG version used for the sample rate.
+fn max_mp3_bitrate_kbps(sample_rate: u32) -> u16 {
+    match sample_rate {
+        // MPEG-1
+        32000.. => 320,
+        // MPEG-2
+        16000.. => 160,
+        // MPEG-2.5
+        _ => 64,
+    }
+}
+
+pub fn peak_amplitude(samples: &[f32]) -> f32 {
+    samples
+        .iter()
+        .fold(0.0f32, |peak, sample| peak.max(sample.abs()))
+}
+
+/// Maximum absolute amplitude in each of `buckets` equally sized sections of the audio.
+pub fn waveform_peaks(samples: &[f32], buckets: usize) -> Vec<f32> {
+    (0..buckets)
+        .map(|i| {
+            let start = i * samples.len() / buckets;
+            let end = (i + 1) * samples.len() / buckets;
+            peak_amplitude(&samples[start..end])
+        })
+        .collect()
+}
+
+/// Fingerprint that changes little when the audio only changes in ways that are hard to hear,
+/// like a different encoding or volume, so fingerprints are compared by the number of
+/// differing bits. Each bit tells whether the energy rises from one section of the audio to the
+/// next. Silence at the ends is ignored, because encoders add some.
+pub fn perceptual_hash(samples: &[f32]) -> u64 {
+    const SECTIONS: usize = 65;
+    const SILENCE: f32 = 1e-3;
+
+    let start = samples
+        .iter()
+        .position(|sample| sample.abs() > SILENCE)
+        .unwrap_or(0);
+    let end = samples
+        .iter()
+        .rposition(|sample| sample.abs() > SILENCE)
+        .map_or(start, |end| end + 1);
+    let samples = &samples[start..end];
+    let energies: Vec<f32> = (0..SECTIONS)
+        .map(|i| {
+            let start = i * samples.len() / SECTIONS;
+            let end = (i + 1) * samples.len() / SECTIONS;
+            samples[start..end]
+                .iter()
+                .map(|sample| sample * sample)
+                .sum()
+        })
+        .collect();
+    energies
+        .windows(2)
+        .enumerate()
+        .filter(|(_, pair)| pair[1] > pair[0])
+        .fold(0, |hash, (i, _)| hash | 1 << i)
+}
+
+/// Removes ID3v2 tags at the start and an ID3v1 tag at the end of an MP3 file.
+pub fn strip_id3(mut mp3: &[u8]) -> &[u8] {
+    while mp3.len() >= 10 && mp3.starts_with(b"ID3") {
+        let has_footer = mp3[5] & 0x10 != 0;
+        let size = from_syncsafe(&mp3[6..10]) + 10 + if has_footer { 10 } else { 0 };
+        mp3 = &mp3[size.min(mp3.len())..];
+    }
+    if mp3.len() >= 128 && mp3[mp3.len() - 128..].starts_with(b"TAG") {
+        mp3 = &mp3[..mp3.len() - 128];
+    }
+    mp3
+}
+
+/// Cheap check whether the data is clearly not audio, which is much faster than a full probe.
+/// Providers and proxies sometimes send JSON errors or HTML pages with a success status. Returns
+/// what the data looks like instead. Anything else is left to the decoder, so that audio in other
+/// containers still reaches the codec checks.
+pub fn sniff_non_audio(data: &[u8]) -> Option<&'static str> {
+    let is_frame_sync = data.len() >= 2 && data[0] == 0xFF && data[1] & 0xE0 == 0xE0;
+    if data.starts_with(b"ID3") || is_frame_sync || container_type(data).is_some() {
+        return None;
+    }
+    match data.iter().find(|byte| !byte.is_ascii_whitespace()) {
+        Some(b'{' | b'[') => Some("JSON"),
+        Some(b'<') => Some("HTML"),
+        Some(_) => None,
+        None => Some("empty data"),
+    }
+}
+
+/// MIME type of an audio file, based on its first bytes. Usually this is MP3, but audio in
+/// other formats may be passed through with `--passthrough-unsupported-codecs`.
+pub fn content_type(data: &[u8]) -> &'static str {
+    container_type(data).unwrap_or("audio/mpeg")
+}
+
+/// MIME type of audio in a container other than MP3.
+fn container_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"OggS") {
+        Some("audio/ogg")
+    } else if data.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some("audio/webm")
+    } else if data.starts_with(b"fLaC") {
+        Some("audio/flac")
+    } else if data.starts_with(b"RIFF") {
+        Some("audio/wav")
+    } else {
+        None
+    }
+}
+
+/// An ID3v2.4 tag that only contains a comment.
+pub fn id3_comment_tag(comment: &str) -> Vec<u8> {
+    const UTF8_ENCODING: u8 = 3;
+
+    let mut frame_content = vec![UTF8_ENCODING];
+    frame_content.extend_from_slice(b"eng");
+    // Empty content description.
+    frame_content.push(0);
+    frame_content.extend_from_slice(comment.as_bytes());
+
+    let mut frame = b"COMM".to_vec();
+    frame.extend_from_slice(&to_syncsafe(frame_content.len()));
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(&frame_content);
+
+    let mut tag = b"ID3".to_vec();
+    tag.extend_from_slice(&[4, 0, 0]);
+    tag.extend_from_slice(&to_syncsafe(frame.len()));
+    tag.extend_from_slice(&frame);
+    tag
+}
+
+/// A short Ogg stream with Opus audio, which is recognized but cannot be decoded.
+#[cfg(test)]
+pub fn opus_in_ogg() -> Vec<u8> {
+    fn ogg_page(header_type: u8, granule: u64, sequence: u32, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.extend_from_slice(&[0, header_type]);
+        page.extend_from_slice(&granule.to_le_bytes());
+        // Serial number of the stream.
+        page.extend_from_slice(&1u32.to_le_bytes());
+        page.extend_from_slice(&sequence.to_le_bytes());
+        let crc_offset = page.len();
+        page.extend_from_slice(&[0; 4]);
+        page.extend_from_slice(&[1, packet.len() as u8]);
+        page.extend_from_slice(packet);
+        let crc = page.iter().fold(0u32, |crc, byte| {
+            (0..8).fold(crc ^ ((*byte as u32) << 24), |crc, _| {
+                if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04C1_1DB7
+                } else {
+                    crc << 1
+                }
+            })
+        });
+        page[crc_offset..crc_offset + 4].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    let mut opus_head = b"OpusHead".to_vec();
+    opus_head.extend_from_slice(&[1, 1]);
+    opus_head.extend_from_slice(&312u16.to_le_bytes());
+    opus_head.extend_from_slice(&48000u32.to_le_bytes());
+    opus_head.extend_from_slice(&[0, 0, 0]);
+    let mut opus_tags = b"OpusTags".to_vec();
+    opus_tags.extend_from_slice(&[0; 8]);
+    // A single 20 ms frame of silence.
+    let silence = [0xF8, 0xFF, 0xFE];
+    [
+        ogg_page(2, 0, 0, &opus_head),
+        ogg_page(0, 0, 1, &opus_tags),
+        ogg_page(4, 960, 2, &silence),
+    ]
+    .concat()
+}
+
+fn from_syncsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |value, byte| (value << 7) | (*byte & 0x7f) as usize)
+}
+
+fn to_syncsafe(value: usize) -> [u8; 4] {
+    [
+        (value >> 21) as u8 & 0x7f,
+        (value >> 14) as u8 & 0x7f,
+        (value >> 7) as u8 & 0x7f,
+        value as u8 & 0x7f,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(frequency: f32, amplitude: f32, sample_rate: u32) -> DecodedAudio {
+        DecodedAudio {
+            samples: (0..sample_rate / 2)
+                .map(|i| {
+                    amplitude
+                        * (std::f32::consts::TAU * frequency * i as f32 / sample_rate as f32).sin()
+                })
+                .collect(),
+            right_channel: None,
+            sample_rate,
+        }
+    }
+
+    #[test]
+    fn waveform_has_one_peak_per_bucket() {
+        let samples: Vec<f32> = (0..1000).map(|i| i as f32 / 1000.0).collect();
+        let peaks = waveform_peaks(&samples, 10);
+        assert_eq!(peaks.len(), 10);
+        assert_eq!(peaks[0], 0.099);
+        assert_eq!(peaks[9], 0.999);
+        assert_eq!(waveform_peaks(&samples[..3], 8).len(), 8);
+    }
+
+    #[test]
+    fn loop_trim_starts_and_ends_at_rising_zero_crossings() {
+        // Starts and ends mid-wave, 3.6 periods of 20 samples.
+        let mut samples: Vec<f32> = (0..72)
+            .map(|i| (std::f32::consts::TAU * (i as f32 + 5.5) / 20.0).sin())
+            .collect();
+        trim_to_zero_crossings(&mut samples);
+        assert_eq!(samples.len(), 40);
+        assert!(samples[0] >= 0.0 && samples[0] < 0.2);
+        assert!(samples[samples.len() - 1] < 0.0);
+
+        let mut silence = vec![0.0; 10];
+        trim_to_zero_crossings(&mut silence);
+        assert_eq!(silence.len(), 10);
+    }
+
+    #[test]
+    fn normalizes_to_target_peak() {
+        let mut audio = tone(440.0, 0.1, 24000);
+        let effects = Effects {
+            target_peak_dbfs: Some(ordered_float::NotNan::new(-6.0).unwrap()),
+            ..Effects::default()
+        };
+        effects.validate().unwrap();
+        apply_effects(&mut audio, &effects).unwrap();
+        assert!((peak_amplitude(&audio.samples) - 0.501).abs() < 0.001);
+
+        let with_volume = Effects {
+            volume_factor: ordered_float::NotNan::new(2.0).unwrap(),
+            ..effects
+        };
+        assert!(with_volume.validate().is_err());
+    }
+
+    #[test]
+    fn detects_truncated_encoder_output() {
+        // One second at 64 kbps is 8000 bytes.
+        assert!(check_encoder_output(24000, 7800, 64, 24000).is_ok());
+        assert!(check_encoder_output(24000, 1000, 64, 24000).is_err());
+        assert!(check_encoder_output(24000, 0, 64, 24000).is_err());
+        assert!(check_encoder_output(0, 0, 64, 24000).is_ok());
+    }
+
+    #[test]
+    fn beeps_are_mixed_in_at_their_offset() {
+        let mut audio = tone(440.0, 0.0, 24000);
+        add_beep(&mut audio, 100).unwrap();
+        let beep_start = 100 * 24000 / 1000;
+        assert_eq!(peak_amplitude(&audio.samples[..beep_start]), 0.0);
+        assert!(peak_amplitude(&audio.samples[beep_start..beep_start + 1200]) > 0.4);
+        assert_eq!(peak_amplitude(&audio.samples[beep_start + 1200..]), 0.0);
+        assert!(add_beep(&mut audio, 500).is_err());
+    }
+
+    #[test]
+    fn full_width_right_channel_is_delayed() {
+        let mut audio = tone(440.0, 0.5, 24000);
+        widen_stereo(&mut audio, 1.0);
+        let right_channel = audio.right_channel.as_ref().unwrap();
+        let delay = 15 * 24;
+        assert_eq!(right_channel.len(), audio.samples.len());
+        assert!(right_channel[..delay].iter().all(|sample| *sample == 0.0));
+        assert_eq!(
+            right_channel[delay..],
+            audio.samples[..audio.samples.len() - delay]
+        );
+    }
+
+    #[test]
+    fn strips_id3_tags() {
+        let frames = [0xFF, 0xFB, 0x90, 0x64, 1, 2, 3];
+        let tag = id3_comment_tag("Generated speech");
+        assert_eq!(from_syncsafe(&to_syncsafe(300)), 300);
+        assert_eq!(from_syncsafe(&tag[6..10]) + 10, tag.len());
+
+        let mut tagged = [tag.as_slice(), tag.as_slice(), &frames].concat();
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, 0);
+        tagged.extend_from_slice(&id3v1);
+        assert_eq!(strip_id3(&tagged), frames);
+        assert_eq!(strip_id3(&frames), frames);
+    }
+
+    #[test]
+    fn limits_the_duration() {
+        let mut audio = tone(440.0, 0.5, 24000);
+        assert!(limit_duration(&mut audio, 1000, false).is_ok());
+        assert_eq!(audio.samples.len(), 12000);
+        let err = limit_duration(&mut audio, 200, false).unwrap_err();
+        assert_eq!((err.duration_ms, err.max_duration_ms), (500, 200));
+        limit_duration(&mut audio, 200, true).unwrap();
+        assert_eq!(audio.samples.len(), 4800);
+    }
+
+    /// Encodes all samples in a single call, like before the encoder output was streamed.
+    fn encode_mp3_at_once(audio: &DecodedAudio, settings: &EncodingSettings) -> Vec<u8> {
+        let mut builder = mp3lame_encoder::Builder::new().unwrap();
+        builder.set_num_channels(settings.channels).unwrap();
+        builder.set_sample_rate(audio.sample_rate).unwrap();
+        builder.set_brate(settings.mp3_bitrate().unwrap()).unwrap();
+        builder
+            .set_quality(settings.mp3_quality().unwrap())
+            .unwrap();
+        let mut encoder = builder.build().unwrap();
+        let mut mp3 = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(
+            audio.samples.len(),
+        ));
+        let encoded_size = match &audio.right_channel {
+            Some(right) => encoder.encode(
+                mp3lame_encoder::DualPcm {
+                    left: &audio.samples,
+                    right,
+                },
+                mp3.spare_capacity_mut(),
+            ),
+            None => encoder.encode(
+                mp3lame_encoder::MonoPcm(&audio.samples),
+                mp3.spare_capacity_mut(),
+            ),
+        }
+        .unwrap();
+        unsafe {
+            mp3.set_len(encoded_size);
+        }
+        mp3.reserve(7200);
+        let flushed_size = encoder
+            .flush::<mp3lame_encoder::FlushNoGap>(mp3.spare_capacity_mut())
+            .unwrap();
+        unsafe {
+            mp3.set_len(encoded_size + flushed_size);
+        }
+        mp3
+    }
+
+    #[test]
+    fn incremental_encoding_matches_encoding_at_once() {
+        let mut audio = tone(440.0, 0.5, 24000);
+        // Longer than a single chunk and not a multiple of it.
+        audio.samples = audio.samples.repeat(5);
+        audio.samples.truncate(59_000);
+        let mono = EncodingSettings {
+            bitrate_kbps: 64,
+            quality: 2,
+            sample_rate: None,
+            channels: 1,
+        };
+        assert_eq!(
+            encode_mp3(&audio, &mono).unwrap(),
+            encode_mp3_at_once(&audio, &mono)
+        );
+
+        widen_stereo(&mut audio, 0.5);
+        let stereo = EncodingSettings {
+            channels: 2,
+            ..mono
+        };
+        assert_eq!(
+            encode_mp3(&audio, &stereo).unwrap(),
+            encode_mp3_at_once(&audio, &stereo)
+        );
+    }
+
+    #[test]
+    fn sniffs_non_audio_data() {
+        assert_eq!(sniff_non_audio(&[0xFF, 0xFB, 0x90, 0x64]), None);
+        assert_eq!(sniff_non_audio(b"ID3\x04\x00"), None);
+        assert_eq!(sniff_non_audio(b"  {\"error\": {}}"), Some("JSON"));
+        assert_eq!(sniff_non_audio(b"[]"), Some("JSON"));
+        assert_eq!(sniff_non_audio(b"<!DOCTYPE html>"), Some("HTML"));
+        assert_eq!(sniff_non_audio(b"OggS"), None);
+        assert_eq!(sniff_non_audio(b"fLaC"), None);
+        assert_eq!(sniff_non_audio(b" \n"), Some("empty data"));
+    }
+
+    #[test]
+    fn filters_attenuate_outside_their_band() {
+        let filtered_peak = |frequency, effects: &Effects| {
+            let mut audio = tone(frequency, 0.5, 24000);
+            apply_effects(&mut audio, effects).unwrap();
+            // Skip the filter's settling time.
+            peak_amplitude(&audio.samples[2400..])
+        };
+        let highpass = Effects {
+            highpass_hz: Some(ordered_float::NotNan::new(1000.0).unwrap()),
+            ..Effects::default()
+        };
+        assert!(filtered_peak(100.0, &highpass) < 0.05);
+        assert!(filtered_peak(5000.0, &highpass) > 0.45);
+        let lowpass = Effects {
+            lowpass_hz: Some(ordered_float::NotNan::new(1000.0).unwrap()),
+            ..Effects::default()
+        };
+        assert!(filtered_peak(100.0, &lowpass) > 0.45);
+        assert!(filtered_peak(8000.0, &lowpass) < 0.05);
+
+        assert!(lowpass.validate_cutoffs(24000).is_ok());
+        assert!(lowpass.validate_cutoffs(2000).is_err());
+    }
+
+    #[test]
+    fn reports_unavailable_encoder() {
+        let settings = EncodingSettings {
+            bitrate_kbps: 64,
+            quality: 2,
+            sample_rate: None,
+            channels: 1,
+        };
+        let mut output = Vec::new();
+        let result =
+            encode_mp3_with_builder(|| None, &tone(440.0, 0.5, 24000), &settings, &mut output);
+        assert!(result.unwrap_err().is::<EncoderUnavailable>());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn limiter_prevents_clipping() {
+        let original = tone(440.0, 0.5, 24000);
+        let mut audio = tone(440.0, 0.5, 24000);
+        let effects = Effects {
+            volume_factor: ordered_float::NotNan::new(4.0).unwrap(),
+            limiter: Some(LimiterSettings {
+                threshold_dbfs: ordered_float::NotNan::new(-1.0).unwrap(),
+                lookahead_ms: 5,
+                release_ms: 100,
+            }),
+            ..Effects::default()
+        };
+        apply_effects(&mut audio, &effects).unwrap();
+        assert!(peak_amplitude(&audio.samples) <= 10.0f32.powf(-1.0 / 20.0));
+        let energy = |samples: &[f32]| samples.iter().map(|sample| sample * sample).sum::<f32>();
+        assert!(energy(&audio.samples) > energy(&original.samples));
+    }
+
+    #[test]
+    fn reports_unsupported_codecs() {
+        let err = decode(Bytes::from(opus_in_ogg())).err().unwrap();
+        assert_eq!(err.downcast::<UnsupportedCodec>().unwrap().codec, "Opus");
+        assert_eq!(content_type(&opus_in_ogg()), "audio/ogg");
+    }
+
+    #[test]
+    fn perceptual_hash_ignores_encoding_and_volume() {
+        // Loudness ramping up and down like syllables, at a rate that differs between seeds.
+        let speech_like = |syllables_per_second: f32| {
+            let mut audio = tone(440.0, 0.5, 24000);
+            for (i, sample) in audio.samples.iter_mut().enumerate() {
+                let phase = (i as f32 / 24000.0 * syllables_per_second).fract();
+                *sample *= 0.1 + 1.8 * phase.min(1.0 - phase);
+            }
+            audio
+        };
+        let encoded_hash = |audio: &DecodedAudio, bitrate_kbps| {
+            let settings = EncodingSettings {
+                bitrate_kbps,
+                quality: 2,
+                sample_rate: None,
+                channels: 1,
+            };
+            let mp3 = encode_mp3(audio, &settings).unwrap();
+            perceptual_hash(&decode(Bytes::from(mp3)).unwrap().samples)
+        };
+        let distance = |a: u64, b: u64| (a ^ b).count_ones();
+
+        let original = speech_like(13.0);
+        let hash = encoded_hash(&original, 192);
+        assert!(distance(hash, encoded_hash(&original, 64)) <= 4);
+        let mut quiet = speech_like(13.0);
+        apply_effects(
+            &mut quiet,
+            &Effects {
+                volume_factor: ordered_float::NotNan::new(0.5).unwrap(),
+                ..Effects::default()
+            },
+        )
+        .unwrap();
+        assert!(distance(hash, encoded_hash(&quiet, 192)) <= 4);
+        assert!(distance(hash, encoded_hash(&speech_like(7.0), 192)) >= 16);
+    }
+
+    #[test]
+    fn encoder_output_check_allows_capped_bitrates() {
+        for sample_rate in [8000, 11025, 12000, 16000, 24000, 48000] {
+            let settings = EncodingSettings {
+                bitrate_kbps: 320,
+                quality: 2,
+                sample_rate: Some(sample_rate),
+                channels: 1,
+            };
+            let audio = tone(440.0, 0.5, 24000);
+            let mp3 = encode_mp3(&audio, &settings);
+            assert!(mp3.is_ok(), "{}: {:?}", sample_rate, mp3.err());
+            let samples = audio.samples.len() * sample_rate as usize / 24000;
+            let mp3 = mp3.unwrap();
+            assert!(check_encoder_output(samples, mp3.len(), 320, sample_rate).is_ok());
+            assert!(check_encoder_output(samples, mp3.len() / 8, 320, sample_rate).is_err());
+        }
+    }
+
+    #[test]
+    fn resampling_does_not_alias() {
+        let audio = tone(440.0, 0.5, 24000);
+        assert!(matches!(
+            resample(&audio.samples, 24000, 24000),
+            Cow::Borrowed(_)
+        ));
+        let resampled = resample(&audio.samples, 24000, 8000);
+        assert_eq!(resampled.len(), audio.samples.len() / 3);
+        assert!(peak_amplitude(&resampled) > 0.45);
+
+        // Would alias to 3 kHz without filtering.
+        let high = tone(11000.0, 0.5, 24000);
+        let resampled = resample(&high.samples, 24000, 8000);
+        assert!(peak_amplitude(&resampled[100..]) < 0.05);
+    }
+
+    #[test]
+    fn stereo_width_makes_the_encoded_channels_differ() {
+        let settings = EncodingSettings {
+            bitrate_kbps: 128,
+            quality: 2,
+            sample_rate: None,
+            channels: 2,
+        };
+        let encoded_channels = |width: f32| {
+            let mut audio = tone(440.0, 0.5, 24000);
+            let effects = Effects {
+                stereo_width: ordered_float::NotNan::new(width).unwrap(),
+                ..Effects::default()
+            };
+            apply_effects(&mut audio, &effects).unwrap();
+            let mp3 = encode_mp3(&audio, &settings).unwrap();
+            let (channels, _) = decode_channels(Bytes::from(mp3)).unwrap();
+            assert_eq!(channels.len(), 2);
+            channels
+        };
+        let difference = |channels: &[Vec<f32>]| {
+            let difference: Vec<f32> = channels[0]
+                .iter()
+                .zip(&channels[1])
+                .map(|(left, right)| left - right)
+                .collect();
+            peak_amplitude(&difference)
+        };
+        assert_eq!(difference(&encoded_channels(0.0)), 0.0);
+        assert!(difference(&encoded_channels(0.5)) > 0.1);
+    }
+}
@@ -1,26 +1,168 @@
+mod audio;
+mod script;
+mod signing;
+
+use actix_web::dev::Service as _;
 use actix_web::{web::Bytes, HttpResponse, HttpServer, Responder};
 use clap::Parser;
-use config;
 use lru_mem::{HeapSize, LruCache};
 use parking_lot::Mutex;
-use std::io::Cursor;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::net::TcpListener;
-use std::{hash::Hash, sync::Arc};
-use symphonia::core::audio::{AudioBuffer, Signal};
-use symphonia::core::codecs::DecoderOptions;
+use std::sync::Arc;
+
+const MAX_TEXT_LENGTH: usize = 100;
+/// Seconds for which clients may keep audio responses.
+const CLIENT_CACHE_DURATION: u64 = 60 * 60 * 24 * 7;
+const OPENAI_SPEECH_URL: &str = "https://api.openai.com/v1/audio/speech";
+/// Sample rate of the audio returned by the speech API.
+const UPSTREAM_SAMPLE_RATE: u32 = 24000;
 
 struct AppState {
     secrets: Secrets,
+    args: Args,
+    prefetch_permits: Arc<tokio::sync::Semaphore>,
     shared: Arc<Mutex<SharedState>>,
+    /// Bytes of memory that are available, usually [`available_memory`].
+    available_memory: fn() -> Option<u64>,
+}
+
+impl AppState {
+    /// New cache entries are not inserted while the system is low on memory. Existing entries
+    /// are still served.
+    fn caching_paused(&self) -> bool {
+        let Some(min_available_memory) = self.args.min_available_memory else {
+            return false;
+        };
+        match (self.available_memory)() {
+            Some(available) if available < min_available_memory.as_u64() => {
+                println!(
+                    "Skipping cache insert: {} bytes of memory available",
+                    available
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn available_memory() -> Option<u64> {
+    parse_available_memory(&std::fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+fn parse_available_memory(meminfo: &str) -> Option<u64> {
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kibibytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kibibytes * 1024)
 }
 
 struct SharedState {
-    speech_cache: LruCache<CacheKey, Vec<u8>>,
+    speech_cache: SpeechCache,
+    waveform_cache: LruCache<WaveformCacheKey, Vec<f32>>,
+    /// Upstream audio for which the requested effects turned out to be invalid, e.g. because a
+    /// beep marker is beyond its end. Retrying such requests does not synthesize them again.
+    rejected_audio_cache: LruCache<OpenaiSpeechRequestInfo, Vec<u8>>,
+    upstream_status: Option<UpstreamStatus>,
+    /// Nonces of signed requests seen recently, with the time until their signature is valid.
+    used_nonces: HashMap<String, u64>,
+    /// Upstream requests that are in progress or finished very recently.
+    upstream_fetches: HashMap<OpenaiSpeechRequestInfo, UpstreamFetch>,
+}
+
+impl SharedState {
+    fn new(args: &Args) -> Self {
+        Self {
+            speech_cache: SpeechCache::new(16 * 1024 * 1024, &args.voice_cache_sizes),
+            waveform_cache: LruCache::new(1024 * 1024),
+            rejected_audio_cache: LruCache::new(1024 * 1024),
+            upstream_status: None,
+            used_nonces: HashMap::new(),
+            upstream_fetches: HashMap::new(),
+        }
+    }
+}
+
+/// Voices with a configured budget get their own partition, so that they cannot be evicted by
+/// other voices. All other voices share a cache of fixed size. Partitions are allocated in
+/// addition to it, so they do not make the shared cache smaller.
+struct SpeechCache {
+    voice_partitions: HashMap<String, LruCache<CacheKey, CachedSpeech>>,
+    shared: LruCache<CacheKey, CachedSpeech>,
+}
+
+#[derive(Clone, Debug)]
+struct CachedSpeech {
+    audio: Vec<u8>,
+    perceptual_hash: Option<u64>,
+}
+
+impl HeapSize for CachedSpeech {
+    fn heap_size(&self) -> usize {
+        self.audio.heap_size()
+    }
+}
+
+impl SpeechCache {
+    fn new(shared_size: usize, voice_sizes: &[(String, byte_unit::Byte)]) -> Self {
+        Self {
+            voice_partitions: voice_sizes
+                .iter()
+                .map(|(voice, size)| (voice.clone(), LruCache::new(size.as_u64() as usize)))
+                .collect(),
+            shared: LruCache::new(shared_size),
+        }
+    }
+
+    fn partition(&mut self, key: &CacheKey) -> &mut LruCache<CacheKey, CachedSpeech> {
+        self.voice_partitions
+            .get_mut(&key.request.voice)
+            .unwrap_or(&mut self.shared)
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<&CachedSpeech> {
+        self.partition(key).get(key)
+    }
+
+    fn contains(&self, key: &CacheKey) -> bool {
+        self.voice_partitions
+            .get(&key.request.voice)
+            .unwrap_or(&self.shared)
+            .contains(key)
+    }
+
+    fn insert(&mut self, key: CacheKey, speech: CachedSpeech) {
+        let _ = self.partition(&key).insert(key, speech);
+    }
+}
+
+struct UpstreamStatus {
+    reachable: bool,
+    checked_at: std::time::Instant,
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
 struct Secrets {
     openai_key: String,
+    /// When set, requests have to be signed with this key, see [`signing::verify`].
+    signing_key: Option<String>,
+}
+
+/// Reads the secrets file if it exists. Keys given in the environment take precedence.
+fn load_secrets(
+    secrets_file: &str,
+    openai_key: Option<String>,
+    signing_key: Option<String>,
+) -> Result<Secrets, config::ConfigError> {
+    config::Config::builder()
+        .add_source(config::File::with_name(secrets_file).required(false))
+        .set_override_option("openai_key", openai_key)?
+        .set_override_option("signing_key", signing_key)?
+        .build()?
+        .try_deserialize()
 }
 
 #[actix_web::get("/")]
@@ -28,11 +170,42 @@ async fn get_index() -> impl Responder {
     HttpResponse::Ok().body("A simple wrapper around a text-to-speech API for short pieces of text")
 }
 
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Deserialize, Debug, Clone)]
 struct SpeechRequestParams {
+    /// Not used for sprites, which get a list of phrases instead.
+    #[serde(default)]
     text: String,
     voice: Option<String>,
     volume: Option<ordered_float::NotNan<f32>>,
+    bitrate: Option<u16>,
+    quality: Option<u8>,
+    target_peak_dbfs: Option<ordered_float::NotNan<f32>>,
+    loop_ready: Option<bool>,
+    /// Comma separated offsets in milliseconds at which beeps are mixed into the audio.
+    #[serde(default, deserialize_with = "deserialize_comma_separated")]
+    beep_markers: Vec<u32>,
+    stereo_width: Option<ordered_float::NotNan<f32>>,
+    highpass_hz: Option<ordered_float::NotNan<f32>>,
+    lowpass_hz: Option<ordered_float::NotNan<f32>>,
+    /// Shorten text that is too long instead of rejecting it.
+    truncate: Option<bool>,
+    seed: Option<u32>,
+    /// Small mono output for clients with little bandwidth.
+    low_bandwidth: Option<bool>,
+}
+
+fn deserialize_comma_separated<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = <String as serde::Deserialize>::deserialize(deserializer)?;
+    value
+        .split(',')
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse().map_err(serde::de::Error::custom))
+        .collect()
 }
 
 #[derive(serde::Serialize, Debug, Hash, PartialEq, Eq, Clone)]
@@ -41,222 +214,2511 @@ struct OpenaiSpeechRequestInfo {
     voice: String,
     input: String,
     response_format: String,
+    /// Only sent when given, for providers that support more deterministic output. Different
+    /// seeds are cached separately.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u32>,
 }
 
-#[derive(Debug, Hash, PartialEq, Eq)]
+#[derive(serde::Serialize, Debug, Clone, Hash, PartialEq, Eq)]
 struct CacheKey {
     request: OpenaiSpeechRequestInfo,
-    volume_factor: ordered_float::NotNan<f32>,
+    effects: audio::Effects,
+    encoding: audio::EncodingSettings,
 }
 
 impl HeapSize for OpenaiSpeechRequestInfo {
     fn heap_size(&self) -> usize {
-        return self.model.capacity()
+        self.model.capacity()
             + self.voice.capacity()
             + self.input.capacity()
-            + self.response_format.capacity();
+            + self.response_format.capacity()
+    }
+}
+
+impl HeapSize for audio::Effects {
+    fn heap_size(&self) -> usize {
+        self.beep_markers_ms.capacity() * std::mem::size_of::<u32>()
+    }
+}
+
+impl HeapSize for audio::EncodingSettings {
+    fn heap_size(&self) -> usize {
+        0
     }
 }
 
 impl HeapSize for CacheKey {
     fn heap_size(&self) -> usize {
-        return self.request.heap_size() + self.volume_factor.heap_size();
+        self.request.heap_size() + self.effects.heap_size() + self.encoding.heap_size()
+    }
+}
+
+/// Reports the result of the last periodic upstream check, so that probes never cause upstream
+/// requests themselves.
+#[actix_web::get("/ready")]
+async fn get_ready(state: actix_web::web::Data<AppState>) -> impl Responder {
+    let shared = state.shared.lock();
+    let Some(status) = &shared.upstream_status else {
+        return HttpResponse::ServiceUnavailable().body("upstream not checked yet");
+    };
+    let body = serde_json::json!({
+        "upstream_reachable": status.reachable,
+        "checked_seconds_ago": status.checked_at.elapsed().as_secs(),
+    });
+    if status.reachable {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+async fn check_upstream_periodically(
+    url: String,
+    secrets: Secrets,
+    shared: Arc<Mutex<SharedState>>,
+    interval: std::time::Duration,
+) {
+    let client = reqwest::Client::new();
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let reachable = match client
+            .get(&url)
+            .bearer_auth(&secrets.openai_key)
+            .send()
+            .await
+        {
+            Ok(res) => res.status().is_success(),
+            Err(_) => false,
+        };
+        let previously_reachable = shared
+            .lock()
+            .upstream_status
+            .replace(UpstreamStatus {
+                reachable,
+                checked_at: std::time::Instant::now(),
+            })
+            .map(|status| status.reachable);
+        if previously_reachable != Some(reachable) {
+            println!("Upstream reachable: {}", reachable);
+        }
+    }
+}
+
+/// Lets clients control how caches after the server treat the response, e.g. `no-store` for
+/// sensitive one-off prompts. This does not affect the server side cache.
+#[derive(serde::Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum ClientCachePolicy {
+    NoStore,
+    Public,
+    Private,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct ClientCacheRequestParams {
+    client_cache: Option<ClientCachePolicy>,
+}
+
+fn apply_client_cache_policy(response: &mut HttpResponse, policy: Option<ClientCachePolicy>) {
+    let value = match policy {
+        None => return,
+        Some(ClientCachePolicy::NoStore) => "no-store".to_string(),
+        Some(ClientCachePolicy::Public) => format!("public, max-age={}", CLIENT_CACHE_DURATION),
+        Some(ClientCachePolicy::Private) => format!("private, max-age={}", CLIENT_CACHE_DURATION),
+    };
+    response.headers_mut().insert(
+        actix_web::http::header::CACHE_CONTROL,
+        actix_web::http::header::HeaderValue::from_str(&value).unwrap(),
+    );
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct ExplainRequestParams {
+    explain: Option<bool>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct DelayRequestParams {
+    /// Only used in dev mode, see [`simulate_delay`].
+    simulate_delay_ms: Option<u64>,
+}
+
+/// Lets client developers test their loading states. This is only possible in debug builds
+/// with `--dev-mode` and does not affect what is cached.
+async fn simulate_delay(args: &Args, info: &DelayRequestParams) {
+    const MAX_DELAY_MS: u64 = 30_000;
+
+    if !cfg!(debug_assertions) || !args.dev_mode {
+        return;
+    }
+    if let Some(delay_ms) = info.simulate_delay_ms {
+        let delay = std::time::Duration::from_millis(delay_ms.min(MAX_DELAY_MS));
+        actix_web::rt::time::sleep(delay).await;
     }
 }
 
 #[actix_web::get("/speak")]
 async fn get_speech(
+    req: actix_web::HttpRequest,
     state: actix_web::web::Data<AppState>,
     info: actix_web::web::Query<SpeechRequestParams>,
+    explain_info: actix_web::web::Query<ExplainRequestParams>,
+    prefetch_info: actix_web::web::Query<PrefetchRequestParams>,
+    delay_info: actix_web::web::Query<DelayRequestParams>,
+    client_cache_info: actix_web::web::Query<ClientCacheRequestParams>,
 ) -> impl Responder {
-    if info.text.len() > 100 {
-        return HttpResponse::BadRequest().body("text too long");
+    let resolved = resolve_request(&state.args, &info);
+    let is_public = is_public_phrase(&state.args, &info.text, &resolved.cache_key);
+    if let Err(response) = verify_signature(&state, &req, is_public) {
+        return response;
+    }
+    simulate_delay(&state.args, &delay_info).await;
+    if explain_info.explain.unwrap_or(false) {
+        return explain_speech(&state, &resolved.cache_key);
+    }
+    let text = resolved.cache_key.request.input.clone();
+    match get_speech_audio(&state, resolved.cache_key).await {
+        Ok(audio) => {
+            prefetch_speech(&state, &info, &prefetch_info.prefetch, is_public);
+            let mut response = speech_response(&state.args, audio.data);
+            apply_client_cache_policy(&mut response, client_cache_info.client_cache);
+            if let Some(perceptual_hash) = audio.perceptual_hash {
+                response.headers_mut().insert(
+                    actix_web::http::header::HeaderName::from_static("x-audio-perceptual-hash"),
+                    actix_web::http::header::HeaderValue::from_str(&format!(
+                        "{:016x}",
+                        perceptual_hash
+                    ))
+                    .unwrap(),
+                );
+            }
+            if let Some(voice) = audio.substituted_voice {
+                response.headers_mut().insert(
+                    actix_web::http::header::HeaderName::from_static("x-substituted-voice"),
+                    actix_web::http::header::HeaderValue::from_str(&voice).unwrap(),
+                );
+            }
+            if resolved.truncated_text {
+                let encoded_text = percent_encoding::utf8_percent_encode(
+                    &text,
+                    percent_encoding::NON_ALPHANUMERIC,
+                )
+                .to_string();
+                response.headers_mut().insert(
+                    actix_web::http::header::HeaderName::from_static("x-truncated-text"),
+                    actix_web::http::header::HeaderValue::from_str(&encoded_text).unwrap(),
+                );
+            }
+            response
+        }
+        Err(response) => response,
     }
+}
 
-    let volume_factor = info
-        .volume
-        .unwrap_or(ordered_float::NotNan::new(1.0).unwrap());
+fn verify_signature(
+    state: &AppState,
+    req: &actix_web::HttpRequest,
+    is_public: bool,
+) -> Result<(), HttpResponse> {
+    let Some(signing_key) = &state.secrets.signing_key else {
+        return Ok(());
+    };
+    if is_public {
+        return Ok(());
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    signing::verify(
+        signing_key.as_bytes(),
+        req.query_string(),
+        now,
+        state.args.signature_max_age,
+        &mut state.shared.lock().used_nonces,
+    )
+    .map_err(|err| HttpResponse::Unauthorized().body(err.to_string()))
+}
 
-    let openai_params = OpenaiSpeechRequestInfo {
-        model: "tts-1".to_string(),
-        voice: info.voice.clone().unwrap_or("echo".to_string()),
-        input: info.text.clone(),
-        response_format: "mp3".to_string(),
+/// Public phrases can be requested without a signature, but only with default parameters.
+/// Otherwise unsigned clients could still cause new upstream requests, e.g. by changing the seed.
+fn is_public_phrase(args: &Args, text: &str, cache_key: &CacheKey) -> bool {
+    let has_default_parameters = cache_key.request.seed.is_none()
+        && cache_key.effects == audio::Effects::default()
+        && cache_key.encoding == args.default_encoding(&cache_key.request.response_format);
+    has_default_parameters
+        && args
+            .public_phrases
+            .iter()
+            .any(|(public_voice, public_text)| {
+                *public_voice == cache_key.request.voice && public_text == text
+            })
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct PrefetchRequestParams {
+    /// JSON array of phrases that are likely requested next with the same settings.
+    #[serde(default, deserialize_with = "deserialize_json")]
+    prefetch: Vec<String>,
+}
+
+fn deserialize_json<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::de::DeserializeOwned,
+{
+    let value = <String as serde::Deserialize>::deserialize(deserializer)?;
+    serde_json::from_str(&value).map_err(serde::de::Error::custom)
+}
+
+/// Synthesizes the given phrases in the background so that they are cached when the client
+/// asks for them. Phrases are skipped when too much prefetching is going on already.
+fn prefetch_speech(
+    state: &actix_web::web::Data<AppState>,
+    info: &SpeechRequestParams,
+    phrases: &[String],
+    is_public: bool,
+) {
+    const MAX_PHRASES_PER_REQUEST: usize = 8;
+
+    // The signature was not checked for public phrases, so the hints cannot be trusted either.
+    let public_only = state.secrets.signing_key.is_some() && is_public;
+    for phrase in phrases.iter().take(MAX_PHRASES_PER_REQUEST) {
+        let info = SpeechRequestParams {
+            text: phrase.clone(),
+            ..info.clone()
+        };
+        let cache_key = resolve_request(&state.args, &info).cache_key;
+        if public_only && !is_public_phrase(&state.args, &info.text, &cache_key) {
+            continue;
+        }
+        let Ok(permit) = state.prefetch_permits.clone().try_acquire_owned() else {
+            return;
+        };
+        let state = state.clone();
+        actix_web::rt::spawn(async move {
+            let _ = get_speech_audio(&state, cache_key).await;
+            drop(permit);
+        });
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct SpriteRequestParams {
+    /// JSON array of the phrases in the sprite.
+    #[serde(default, deserialize_with = "deserialize_json")]
+    phrases: Vec<String>,
+}
+
+/// Byte range of a phrase within the sprite.
+#[derive(serde::Serialize, Debug)]
+struct SpriteClip {
+    start: usize,
+    end: usize,
+}
+
+/// Serves many short phrases as a single MP3 file, which saves requests for clients that need
+/// lots of small clips. The byte range of every phrase is listed in the `x-sprite-clips` header
+/// in the order of the phrases. Each phrase is cached on its own.
+#[actix_web::get("/speak/sprite")]
+async fn get_speech_sprite(
+    req: actix_web::HttpRequest,
+    state: actix_web::web::Data<AppState>,
+    info: actix_web::web::Query<SpeechRequestParams>,
+    sprite_info: actix_web::web::Query<SpriteRequestParams>,
+    client_cache_info: actix_web::web::Query<ClientCacheRequestParams>,
+) -> impl Responder {
+    let phrases = &sprite_info.phrases;
+    if phrases.is_empty() || phrases.len() > state.args.max_sprite_clips {
+        return HttpResponse::BadRequest().body(format!(
+            "sprites need between 1 and {} phrases",
+            state.args.max_sprite_clips
+        ));
+    }
+    let cache_keys: Vec<CacheKey> = phrases
+        .iter()
+        .map(|phrase| {
+            let info = SpeechRequestParams {
+                text: phrase.clone(),
+                ..info.0.clone()
+            };
+            resolve_request(&state.args, &info).cache_key
+        })
+        .collect();
+    // The `text` parameter is not synthesized here, so only the phrases decide whether the
+    // sprite is public.
+    let is_public = phrases
+        .iter()
+        .zip(&cache_keys)
+        .all(|(phrase, cache_key)| is_public_phrase(&state.args, phrase, cache_key));
+    if let Err(response) = verify_signature(&state, &req, is_public) {
+        return response;
+    }
+
+    let mut sprite = Vec::new();
+    let mut clips = Vec::new();
+    for cache_key in cache_keys {
+        let audio = match get_speech_audio(&state, cache_key).await {
+            Ok(audio) => audio,
+            Err(response) => return response,
+        };
+        // Tags between the clips would not be skipped by all players.
+        let start = sprite.len();
+        sprite.extend_from_slice(audio::strip_id3(&audio.data));
+        clips.push(SpriteClip {
+            start,
+            end: sprite.len(),
+        });
+    }
+
+    let mut response = audio_response(&state.args, sprite);
+    apply_client_cache_policy(&mut response, client_cache_info.client_cache);
+    response.headers_mut().insert(
+        actix_web::http::header::HeaderName::from_static("x-sprite-clips"),
+        actix_web::http::header::HeaderValue::from_str(&serde_json::to_string(&clips).unwrap())
+            .unwrap(),
+    );
+    response
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct WaveformRequestParams {
+    buckets: Option<usize>,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq)]
+struct WaveformCacheKey {
+    speech: CacheKey,
+    buckets: usize,
+}
+
+impl HeapSize for WaveformCacheKey {
+    fn heap_size(&self) -> usize {
+        self.speech.heap_size()
+    }
+}
+
+#[actix_web::get("/speak/waveform")]
+async fn get_speech_waveform(
+    req: actix_web::HttpRequest,
+    state: actix_web::web::Data<AppState>,
+    info: actix_web::web::Query<SpeechRequestParams>,
+    waveform_info: actix_web::web::Query<WaveformRequestParams>,
+) -> impl Responder {
+    const MAX_BUCKETS: usize = 4096;
+
+    let speech_key = resolve_request(&state.args, &info).cache_key;
+    let is_public = is_public_phrase(&state.args, &info.text, &speech_key);
+    if let Err(response) = verify_signature(&state, &req, is_public) {
+        return response;
+    }
+    let buckets = waveform_info.buckets.unwrap_or(state.args.waveform_buckets);
+    if buckets == 0 || buckets > MAX_BUCKETS {
+        return HttpResponse::BadRequest().body("invalid number of buckets");
+    }
+
+    let cache_key = WaveformCacheKey {
+        speech: speech_key.clone(),
+        buckets,
     };
+    let cached = state.shared.lock().waveform_cache.get(&cache_key).cloned();
+    if let Some(peaks) = cached {
+        return HttpResponse::Ok().json(peaks);
+    }
 
-    let cache_key = CacheKey {
-        request: openai_params.clone(),
-        volume_factor,
+    let audio = match get_speech_audio(&state, speech_key).await {
+        Ok(audio) => audio,
+        Err(response) => return response,
+    };
+    let substituted = audio.substituted_voice.is_some();
+    let peaks = match audio::decode(Bytes::from(audio.data)) {
+        Ok(decoded) => audio::waveform_peaks(&decoded.samples, buckets),
+        Err(err) => {
+            return HttpResponse::InternalServerError().body(format!("Error: {:?}", err));
+        }
     };
+    if !substituted && !state.caching_paused() {
+        let _ = state
+            .shared
+            .lock()
+            .waveform_cache
+            .insert(cache_key, peaks.clone());
+    }
+    HttpResponse::Ok().json(peaks)
+}
 
-    const CACHE_DURATION: u64 = 60 * 60 * 24 * 7;
+/// The text that is synthesized, which is cut at a word boundary if the client allows it. Also
+/// returns whether the text was cut.
+fn resolve_text(args: &Args, info: &SpeechRequestParams) -> (String, bool) {
+    let text = transform_text(args, clean_text(args, &info.text));
+    if !info.truncate.unwrap_or(false) || text.len() <= MAX_TEXT_LENGTH {
+        return (text, false);
+    }
+    let mut end = MAX_TEXT_LENGTH;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let ends_at_word_boundary = text[end..].starts_with(char::is_whitespace);
+    if !ends_at_word_boundary {
+        if let Some(word_start) = text[..end].rfind(char::is_whitespace) {
+            end = word_start;
+        }
+    }
+    (text[..end].trim_end().to_string(), true)
+}
 
-    match state.shared.lock().speech_cache.get(&cache_key) {
-        Some(cached) => {
-            return HttpResponse::Ok()
-                .content_type("audio/mpeg")
-                .insert_header((
-                    actix_web::http::header::CACHE_CONTROL,
-                    format!("max-age={}", CACHE_DURATION),
-                ))
-                .body(Bytes::from(cached.clone()));
+/// Applies `--text-script`. The text is used unchanged when the script fails, e.g. because it
+/// took too long.
+fn transform_text(args: &Args, text: String) -> String {
+    let Some(script) = &args.text_script else {
+        return text;
+    };
+    match script.transform(&text) {
+        Ok(transformed) => transformed,
+        Err(err) => {
+            eprintln!(
+                "Text script failed: text={} error={}",
+                text_for_log(&text, args.log_text),
+                err
+            );
+            text
         }
-        None => {}
     }
+}
 
-    let client = reqwest::Client::new();
-    match client
-        .post("https://api.openai.com/v1/audio/speech")
-        .bearer_auth(state.secrets.openai_key.clone())
-        .json(&openai_params)
-        .send()
-        .await
+/// Text pasted from rich sources often contains invisible characters and unusual spaces. With
+/// `--clean-text`, zero-width characters are removed and runs of whitespace, including
+/// non-breaking spaces, become a single space.
+fn clean_text(args: &Args, text: &str) -> String {
+    const ZERO_WIDTH_CHARACTERS: [char; 5] =
+        ['\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}'];
+
+    if !args.clean_text {
+        return text.to_string();
+    }
+    text.split(|c: char| c.is_whitespace())
+        .map(|word| word.replace(ZERO_WIDTH_CHARACTERS, ""))
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A speech request with all parameters resolved.
+struct ResolvedRequest {
+    cache_key: CacheKey,
+    /// The text was cut to fit, see `truncate`.
+    truncated_text: bool,
+}
+
+/// Resolves the cache key for the audio the client asked for, independent of how it is stored.
+/// This is the only place where request parameters are turned into a key. Defaults are filled
+/// in and values are normalized, so that requests for the same audio get the same key
+/// regardless of parameter order or which defaults are given explicitly. The text script runs
+/// here, so this should only be called once per request.
+fn resolve_request(args: &Args, info: &SpeechRequestParams) -> ResolvedRequest {
+    let (input, truncated_text) = resolve_text(args, info);
+    let request = OpenaiSpeechRequestInfo {
+        model: "tts-1".to_string(),
+        voice: info.voice.clone().unwrap_or("echo".to_string()),
+        input,
+        response_format: "mp3".to_string(),
+        seed: info.seed,
+    };
+    let low_bandwidth = info.low_bandwidth.unwrap_or(false);
+    let default_encoding = if low_bandwidth {
+        audio::EncodingSettings::LOW_BANDWIDTH
+    } else {
+        args.default_encoding(&request.response_format)
+    };
+    let stereo_width = info
+        .stereo_width
+        .unwrap_or(ordered_float::NotNan::new(0.0).unwrap());
+    let volume_factor = info
+        .volume
+        .unwrap_or(ordered_float::NotNan::new(1.0).unwrap());
+    // Beeps are mixed in independently, so their order does not matter.
+    let mut beep_markers_ms = info.beep_markers.clone();
+    beep_markers_ms.sort_unstable();
+    let cache_key = CacheKey {
+        effects: audio::Effects {
+            volume_factor,
+            target_peak_dbfs: info.target_peak_dbfs,
+            loop_ready: info.loop_ready.unwrap_or(false),
+            beep_markers_ms,
+            stereo_width,
+            highpass_hz: info.highpass_hz,
+            lowpass_hz: info.lowpass_hz,
+            // Only boosted audio can exceed the headroom.
+            limiter: args.limiter().filter(|_| volume_factor.into_inner() > 1.0),
+        },
+        encoding: audio::EncodingSettings {
+            bitrate_kbps: info.bitrate.unwrap_or(default_encoding.bitrate_kbps),
+            quality: info.quality.unwrap_or(default_encoding.quality),
+            channels: if stereo_width != 0.0 && !low_bandwidth {
+                2
+            } else {
+                default_encoding.channels
+            },
+            ..default_encoding
+        },
+        request,
+    };
+    ResolvedRequest {
+        cache_key,
+        truncated_text,
+    }
+}
+
+fn validate_request(cache_key: &CacheKey) -> Result<(), HttpResponse> {
+    if cache_key.request.input.is_empty() {
+        return Err(HttpResponse::BadRequest().body("text is missing"));
+    }
+    if cache_key.request.input.len() > MAX_TEXT_LENGTH {
+        return Err(HttpResponse::BadRequest().body("text too long"));
+    }
+    if let Err(err) = cache_key
+        .effects
+        .validate()
+        .and(cache_key.encoding.validate())
     {
-        Err(err) => HttpResponse::InternalServerError().body(format!("Error: {:?}", err)),
-        Ok(res) => {
-            if res.status() != 200 {
-                return HttpResponse::InternalServerError().body(format!("Invalid: {:?}", res));
-            }
-            let result_bytes = res.bytes().await.unwrap();
-            let result_bytes = apply_volume_factor(result_bytes.clone(), volume_factor)
-                .unwrap_or_else(|_| result_bytes.to_vec());
-            let _ = state
-                .shared
-                .lock()
-                .speech_cache
-                .insert(cache_key, result_bytes.clone().into());
-            HttpResponse::Ok()
-                .content_type("audio/mpeg")
-                .insert_header((
-                    actix_web::http::header::CACHE_CONTROL,
-                    format!("max-age={}", CACHE_DURATION),
-                ))
-                .body(result_bytes)
+        return Err(HttpResponse::BadRequest().body(err.to_string()));
+    }
+    // Checked before the audio is synthesized, so that invalid requests are not billed.
+    let sample_rate = cache_key
+        .encoding
+        .sample_rate
+        .map_or(UPSTREAM_SAMPLE_RATE, |rate| rate.min(UPSTREAM_SAMPLE_RATE));
+    if let Err(err) = cache_key.effects.validate_cutoffs(sample_rate) {
+        return Err(HttpResponse::BadRequest().body(err.to_string()));
+    }
+    if cache_key.effects.stereo_width != 0.0 && cache_key.encoding.channels != 2 {
+        return Err(HttpResponse::BadRequest().body("stereo_width requires stereo output"));
+    }
+    Ok(())
+}
+
+/// The key under which the audio is stored in the cache. When effects are applied at serve
+/// time, only the base audio is stored.
+fn storage_cache_key(args: &Args, cache_key: &CacheKey) -> CacheKey {
+    if args.applies_effects_at_serve_time(&cache_key.request.response_format) {
+        CacheKey {
+            request: cache_key.request.clone(),
+            effects: audio::Effects::default(),
+            encoding: args.default_encoding(&cache_key.request.response_format),
+        }
+    } else {
+        CacheKey {
+            request: cache_key.request.clone(),
+            effects: cache_key.effects.clone(),
+            encoding: cache_key.encoding,
         }
     }
 }
 
-fn apply_volume_factor(
-    audio_file: Bytes,
-    volume_factor: ordered_float::NotNan<f32>,
-) -> anyhow::Result<Vec<u8>> {
-    let mss = symphonia::core::io::MediaSourceStream::new(
-        Box::new(Cursor::new(audio_file)),
-        Default::default(),
-    );
-    let probe = symphonia::default::get_probe().format(
-        &Default::default(),
-        mss,
-        &Default::default(),
-        &Default::default(),
-    )?;
-    let mut format = probe.format;
-    let track = &format.tracks().get(0).ok_or(anyhow::anyhow!("No track"))?;
-    let sample_rate = track
-        .codec_params
-        .sample_rate
-        .ok_or(anyhow::anyhow!("No sample rate"))?;
-    let channels = 1;
+/// Describes how a request would be handled without synthesizing anything.
+fn explain_speech(state: &AppState, cache_key: &CacheKey) -> HttpResponse {
+    if let Err(response) = validate_request(cache_key) {
+        return response;
+    }
+    let storage_key = storage_cache_key(&state.args, cache_key);
+    let mut hasher = DefaultHasher::new();
+    storage_key.hash(&mut hasher);
+    let cached = state.shared.lock().speech_cache.contains(&storage_key);
 
-    // Create a decoder for the audio track.
-    let mut decoder =
-        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+    HttpResponse::Ok().json(serde_json::json!({
+        "resolved": cache_key,
+        "cache_key_hash": format!("{:016x}", hasher.finish()),
+        "cached": cached,
+        "upstream": {
+            "url": state.args.upstream_url,
+            "model": cache_key.request.model,
+            "voice": cache_key.request.voice,
+        },
+        "effects_at_serve_time": state
+            .args
+            .applies_effects_at_serve_time(&cache_key.request.response_format),
+        "effects_chain": cache_key.effects.describe(),
+    }))
+}
 
-    let mut all_samples: Vec<f32> = Vec::new();
+/// Audio file for a speech request.
+struct SpeechAudio {
+    data: Vec<u8>,
+    /// Set when the requested voice failed upstream and `--fallback-voice` was used instead.
+    substituted_voice: Option<String>,
+    /// Computed with `--perceptual-hash`.
+    perceptual_hash: Option<u64>,
+}
 
-    // Decode and process the audio packets.
-    while let Ok(packet) = format.next_packet() {
-        // Decode the packet into audio frames.
-        if let Ok(decoded) = decoder.decode(&packet) {
-            let mut converted =
-                AudioBuffer::<f32>::new(decoded.capacity() as u64, decoded.spec().clone());
-            decoded.convert(&mut converted);
-            all_samples.extend(converted.chan(0));
+impl From<CachedSpeech> for SpeechAudio {
+    fn from(speech: CachedSpeech) -> Self {
+        SpeechAudio {
+            data: speech.audio,
+            substituted_voice: None,
+            perceptual_hash: speech.perceptual_hash,
         }
     }
+}
+
+async fn get_speech_audio(
+    state: &AppState,
+    requested_key: CacheKey,
+) -> Result<SpeechAudio, HttpResponse> {
+    validate_request(&requested_key)?;
+    let cache_key = storage_cache_key(&state.args, &requested_key);
+    let CacheKey {
+        request: openai_params,
+        effects,
+        encoding,
+    } = requested_key.clone();
+
+    let log_text = text_for_log(&openai_params.input, state.args.log_text);
+    println!(
+        "Speech request: text={} voice={} volume={}",
+        log_text, openai_params.voice, effects.volume_factor
+    );
+
+    let gain_at_serve_time = state
+        .args
+        .applies_effects_at_serve_time(&openai_params.response_format);
 
-    for sample in all_samples.iter_mut() {
-        *sample *= volume_factor.into_inner();
+    let cached = state.shared.lock().speech_cache.get(&cache_key).cloned();
+    if let Some(cached) = cached {
+        if gain_at_serve_time {
+            return apply_serve_time_effects(
+                &state.args,
+                cached.audio,
+                &openai_params,
+                &effects,
+                &encoding,
+            )
+            .map(|speech| SpeechAudio {
+                perceptual_hash: cached.perceptual_hash,
+                ..speech.into()
+            });
+        }
+        return Ok(cached.into());
     }
 
-    let mut mp3_encoder = mp3lame_encoder::Builder::new().expect("Create LAME builder");
-    mp3_encoder
-        .set_num_channels(channels as u8)
-        .map_err(|_| anyhow::anyhow!("set channels"))?;
-    mp3_encoder
-        .set_sample_rate(sample_rate as u32)
-        .map_err(|_| anyhow::anyhow!("set sample rate"))?;
-    mp3_encoder
-        .set_brate(mp3lame_encoder::Bitrate::Kbps192)
-        .map_err(|_| anyhow::anyhow!("set brate"))?;
-    mp3_encoder
-        .set_quality(mp3lame_encoder::Quality::Best)
-        .map_err(|_| anyhow::anyhow!("set quality"))?;
+    let rejected_audio = state
+        .shared
+        .lock()
+        .rejected_audio_cache
+        .get(&openai_params)
+        .cloned();
+    let fetched = match rejected_audio {
+        Some(audio) => Ok(Bytes::from(audio)),
+        None => {
+            // Concurrent requests that only differ in their effects share a single upstream
+            // request.
+            let fetch = state
+                .shared
+                .lock()
+                .upstream_fetches
+                .entry(openai_params.clone())
+                .or_default()
+                .clone();
+            let fetched = fetch
+                .get_or_init(|| fetch_upstream_audio(state, &openai_params, &log_text))
+                .await
+                .clone();
+            release_upstream_fetch(state, &openai_params, fetch);
+            fetched
+        }
+    };
+    let result_bytes = match fetched {
+        Ok(result_bytes) => result_bytes,
+        Err(err) => {
+            if err.no_audio_produced {
+                if let Some(audio) =
+                    get_speech_audio_with_fallback_voice(state, &requested_key).await
+                {
+                    return audio;
+                }
+            }
+            return Err(HttpResponse::build(err.status).body(err.message));
+        }
+    };
 
-    let mut mp3_encoder = mp3_encoder
-        .build()
-        .map_err(|_| anyhow::anyhow!("initialize LAME encoder"))?;
+    let processed = process_audio(
+        &state.args,
+        result_bytes.clone(),
+        &openai_params,
+        &effects,
+        &encoding,
+    );
+    if let Err(err) = &processed {
+        eprintln!("Cannot process audio: text={} error={}", log_text, err);
+        if err.is::<symphonia::core::errors::Error>() || err.is::<audio::UnsupportedCodec>() {
+            if let Err(write_err) =
+                write_decode_failure(&state.args, &openai_params, &result_bytes, err)
+            {
+                eprintln!("Cannot write decode failure: {:?}", write_err);
+            }
+        }
+    }
+    let too_long = matches!(&processed, Err(err) if err.is::<audio::AudioTooLong>());
+    let caching_paused = state.caching_paused();
+    let invalid_effects = matches!(&processed, Err(err) if err.is::<audio::InvalidEffects>());
+    if invalid_effects && !gain_at_serve_time && !caching_paused {
+        let _ = state
+            .shared
+            .lock()
+            .rejected_audio_cache
+            .insert(openai_params, result_bytes.to_vec());
+    }
+    if gain_at_serve_time {
+        // Only the base audio is stored under the storage key, never the processed audio. The
+        // perceptual hash is computed before effects are applied, so it is the same for both.
+        if !too_long && !caching_paused {
+            let perceptual_hash = processed
+                .as_ref()
+                .ok()
+                .and_then(|speech| speech.perceptual_hash);
+            state.shared.lock().speech_cache.insert(
+                cache_key,
+                CachedSpeech {
+                    audio: result_bytes.to_vec(),
+                    perceptual_hash,
+                },
+            );
+        }
+        return processed_or_fallback(processed, &result_bytes).map(SpeechAudio::from);
+    }
+    let speech = processed_or_fallback(processed, &result_bytes)?;
+    if !caching_paused {
+        state
+            .shared
+            .lock()
+            .speech_cache
+            .insert(cache_key, speech.clone());
+    }
+    Ok(speech.into())
+}
 
-    let input = mp3lame_encoder::MonoPcm(&all_samples);
+/// A failed upstream request. It is shared by all requests that waited for it.
+#[derive(Debug, Clone)]
+struct UpstreamError {
+    status: actix_web::http::StatusCode,
+    message: String,
+    /// Upstream definitely did not synthesize anything, so nothing was billed.
+    no_audio_produced: bool,
+}
 
-    let mut mp3_out_buffer = Vec::new();
-    mp3_out_buffer.reserve(mp3lame_encoder::max_required_buffer_size(all_samples.len()));
-    let encoded_size = mp3_encoder
-        .encode(input, mp3_out_buffer.spare_capacity_mut())
-        .map_err(|_| anyhow::anyhow!("encode"))?;
+type UpstreamFetch = Arc<tokio::sync::OnceCell<Result<Bytes, UpstreamError>>>;
 
-    unsafe {
-        mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+async fn fetch_upstream_audio(
+    state: &AppState,
+    openai_params: &OpenaiSpeechRequestInfo,
+    log_text: &str,
+) -> Result<Bytes, UpstreamError> {
+    let client = reqwest::Client::new();
+    let res = match client
+        .post(&state.args.upstream_url)
+        .bearer_auth(state.secrets.openai_key.clone())
+        .json(openai_params)
+        .send()
+        .await
+    {
+        Err(err) => {
+            eprintln!("Speech request failed: text={} error={:?}", log_text, err);
+            return Err(UpstreamError {
+                status: actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+                message: format!("Error: {:?}", err),
+                // Other errors, like timeouts, may happen after upstream synthesized (and
+                // billed) the audio already.
+                no_audio_produced: err.is_connect(),
+            });
+        }
+        Ok(res) => res,
+    };
+    if res.status() != 200 {
+        eprintln!(
+            "Speech request failed: text={} status={}",
+            log_text,
+            res.status()
+        );
+        let message = format!("Invalid: {:?}", res);
+        // Only temporary failures without a body are worth retrying. Other errors, like an
+        // invalid key, would fail again.
+        let is_temporary = res.status().is_server_error()
+            || res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS;
+        let no_audio_produced = is_temporary && res.bytes().await.is_ok_and(|body| body.is_empty());
+        return Err(UpstreamError {
+            status: actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            message,
+            no_audio_produced,
+        });
     }
-    let encoded_size = mp3_encoder
-        .flush::<mp3lame_encoder::FlushNoGap>(mp3_out_buffer.spare_capacity_mut())
-        .map_err(|_| anyhow::anyhow!("flush"))?;
-    unsafe {
-        mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+    let result_bytes = match res.bytes().await {
+        Err(err) => {
+            eprintln!(
+                "Cannot read speech response: text={} error={:?}",
+                log_text, err
+            );
+            return Err(UpstreamError {
+                status: actix_web::http::StatusCode::BAD_GATEWAY,
+                message: format!("Error: {:?}", err),
+                // Upstream started sending audio, so it was billed already.
+                no_audio_produced: false,
+            });
+        }
+        Ok(result_bytes) => result_bytes,
+    };
+    if state.args.sniff_upstream_audio {
+        if let Some(content) = audio::sniff_non_audio(&result_bytes) {
+            eprintln!(
+                "Speech request returned {} instead of audio: text={}",
+                content, log_text
+            );
+            return Err(UpstreamError {
+                status: actix_web::http::StatusCode::BAD_GATEWAY,
+                message: format!("upstream returned {} instead of audio", content),
+                no_audio_produced: false,
+            });
+        }
     }
-    Ok(mp3_out_buffer)
+    Ok(result_bytes)
 }
 
-#[derive(Parser, Debug)]
-#[command(version, about)]
-struct Args {
-    #[arg(long, default_value = "0.0.0.0")]
-    host: String,
+/// Forgets a finished upstream request after `--coalesce-window-ms`. Until then, requests that
+/// arrive shortly after it finished still use its result.
+fn release_upstream_fetch(
+    state: &AppState,
+    openai_params: &OpenaiSpeechRequestInfo,
+    fetch: UpstreamFetch,
+) {
+    let shared = state.shared.clone();
+    let openai_params = openai_params.clone();
+    let window = std::time::Duration::from_millis(state.args.coalesce_window_ms);
+    let release = move || {
+        let mut shared = shared.lock();
+        let is_same_fetch = shared
+            .upstream_fetches
+            .get(&openai_params)
+            .is_some_and(|current| Arc::ptr_eq(current, &fetch));
+        if is_same_fetch {
+            shared.upstream_fetches.remove(&openai_params);
+        }
+    };
+    if window.is_zero() {
+        release();
+        return;
+    }
+    actix_web::rt::spawn(async move {
+        actix_web::rt::time::sleep(window).await;
+        release();
+    });
+}
 
-    #[arg(long, default_value = "9001")]
-    port: u16,
+/// Synthesizes the text with `--fallback-voice` after the requested voice failed upstream. This
+/// must only be used when upstream definitely did not produce audio, to avoid paying twice. The
+/// result is cached for the fallback voice. Returns `None` when there is no other voice to try.
+async fn get_speech_audio_with_fallback_voice(
+    state: &AppState,
+    requested_key: &CacheKey,
+) -> Option<Result<SpeechAudio, HttpResponse>> {
+    let fallback_voice = state.args.fallback_voice.as_ref()?;
+    if *fallback_voice == requested_key.request.voice {
+        return None;
+    }
+    println!(
+        "Retry speech request with fallback voice: voice={}",
+        fallback_voice
+    );
+    let mut fallback_key = requested_key.clone();
+    fallback_key.request.voice = fallback_voice.clone();
+    let audio = Box::pin(get_speech_audio(state, fallback_key)).await;
+    Some(audio.map(|audio| SpeechAudio {
+        substituted_voice: Some(fallback_voice.clone()),
+        ..audio
+    }))
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let settings = config::Config::builder()
-        .add_source(config::File::with_name("secrets.toml"))
-        .build()
-        .unwrap();
+/// Keeps upstream audio that could not be decoded for later inspection. Only the most recent
+/// files are kept.
+fn write_decode_failure(
+    args: &Args,
+    request: &OpenaiSpeechRequestInfo,
+    audio_file: &[u8],
+    err: &anyhow::Error,
+) -> std::io::Result<()> {
+    let Some(directory) = &args.decode_failure_dir else {
+        return Ok(());
+    };
+    std::fs::create_dir_all(directory)?;
 
-    let args = Args::parse();
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let mut hasher = DefaultHasher::new();
+    audio_file.hash(&mut hasher);
+    let name = format!("{:013}-{:016x}", timestamp, hasher.finish());
 
-    let listener = TcpListener::bind((args.host.clone(), args.port)).expect("Cannot bind to port");
-    let actual_port = listener.local_addr().unwrap().port();
-    println!("Start server on http://{}:{}", args.host, actual_port);
+    let metadata = serde_json::json!({
+        "text": text_for_log(&request.input, args.log_text),
+        "model": request.model,
+        "voice": request.voice,
+        "response_format": request.response_format,
+        "size": audio_file.len(),
+        "error": err.to_string(),
+    });
+    std::fs::write(directory.join(format!("{}.bin", name)), audio_file)?;
+    std::fs::write(
+        directory.join(format!("{}.json", name)),
+        serde_json::to_vec_pretty(&metadata)?,
+    )?;
 
-    let secrets: Secrets = settings.try_deserialize().unwrap();
-    let shared = Arc::new(Mutex::new(SharedState {
-        speech_cache: LruCache::new(16 * 1024 * 1024),
-    }));
+    let mut metadata_files: Vec<_> = std::fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension() == Some("json".as_ref()))
+        .collect();
+    metadata_files.sort();
+    let excess = metadata_files
+        .len()
+        .saturating_sub(args.decode_failure_limit);
+    for path in &metadata_files[..excess] {
+        std::fs::remove_file(path.with_extension("bin"))?;
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
 
-    HttpServer::new(move || {
-        actix_web::App::new()
-            .app_data(actix_web::web::Data::new(AppState {
+fn text_for_log(text: &str, mode: LogTextMode) -> String {
+    const TRUNCATED_LENGTH: usize = 16;
+
+    match mode {
+        LogTextMode::Full => format!("{:?}", text),
+        LogTextMode::Truncated => {
+            if text.chars().count() <= TRUNCATED_LENGTH {
+                format!("{:?}", text)
+            } else {
+                let prefix: String = text.chars().take(TRUNCATED_LENGTH).collect();
+                format!("{:?}...", prefix)
+            }
+        }
+        LogTextMode::Hashed => {
+            let mut hasher = DefaultHasher::new();
+            text.hash(&mut hasher);
+            format!("#{:016x}", hasher.finish())
+        }
+        LogTextMode::Omitted => "<omitted>".to_string(),
+    }
+}
+
+fn speech_response(args: &Args, audio: Vec<u8>) -> HttpResponse {
+    audio_response(args, apply_id3_settings(args, audio))
+}
+
+fn audio_response(args: &Args, audio: Vec<u8>) -> HttpResponse {
+    const CHUNK_SIZE: usize = 16 * 1024;
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(audio::content_type(&audio))
+        .insert_header((
+            actix_web::http::header::CACHE_CONTROL,
+            format!("max-age={}", CLIENT_CACHE_DURATION),
+        ));
+    if args.chunked_responses {
+        let audio = Bytes::from(audio);
+        let chunks: Vec<_> = (0..audio.len())
+            .step_by(CHUNK_SIZE)
+            .map(|start| {
+                Ok::<_, std::convert::Infallible>(
+                    audio.slice(start..(start + CHUNK_SIZE).min(audio.len())),
+                )
+            })
+            .collect();
+        return response.streaming(futures_util::stream::iter(chunks));
+    }
+    response.body(audio)
+}
+
+fn apply_id3_settings(args: &Args, audio: Vec<u8>) -> Vec<u8> {
+    let is_mp3 = audio::content_type(&audio) == "audio/mpeg";
+    if !is_mp3 || (!args.strip_id3 && args.id3_comment.is_none()) {
+        return audio;
+    }
+    let stripped = audio::strip_id3(&audio);
+    match &args.id3_comment {
+        Some(comment) => [&audio::id3_comment_tag(comment), stripped].concat(),
+        None => stripped.to_vec(),
+    }
+}
+
+fn apply_serve_time_effects(
+    args: &Args,
+    base_audio: Vec<u8>,
+    request: &OpenaiSpeechRequestInfo,
+    effects: &audio::Effects,
+    encoding: &audio::EncodingSettings,
+) -> Result<CachedSpeech, HttpResponse> {
+    let processed = process_audio(
+        args,
+        Bytes::from(base_audio.clone()),
+        request,
+        effects,
+        encoding,
+    );
+    if let Err(err) = &processed {
+        eprintln!("Cannot process cached audio: error={}", err);
+    }
+    processed_or_fallback(processed, &base_audio)
+}
+
+/// Falls back to the unprocessed audio when processing fails, unless the requested effects
+/// cannot be applied to this audio at all.
+fn processed_or_fallback(
+    processed: anyhow::Result<CachedSpeech>,
+    unprocessed: &[u8],
+) -> Result<CachedSpeech, HttpResponse> {
+    match processed {
+        Ok(processed) => Ok(processed),
+        Err(err) if err.is::<audio::InvalidEffects>() => {
+            Err(HttpResponse::BadRequest().body(err.to_string()))
+        }
+        Err(err) if err.is::<audio::AudioTooLong>() => {
+            Err(HttpResponse::BadGateway().body(err.to_string()))
+        }
+        Err(err) if err.is::<audio::UnsupportedCodec>() => {
+            Err(HttpResponse::BadGateway().body(format!("Upstream audio: {}", err)))
+        }
+        Err(err) if err.is::<audio::EncoderUnavailable>() => {
+            Err(HttpResponse::ServiceUnavailable().body(err.to_string()))
+        }
+        // The unprocessed audio must not be cached under a key that includes the effects.
+        Err(err) if err.is::<audio::TruncatedEncoderOutput>() => {
+            Err(HttpResponse::InternalServerError().body(err.to_string()))
+        }
+        Err(_) => Ok(CachedSpeech {
+            audio: unprocessed.to_vec(),
+            perceptual_hash: None,
+        }),
+    }
+}
+
+fn process_audio(
+    args: &Args,
+    audio_file: Bytes,
+    request: &OpenaiSpeechRequestInfo,
+    effects: &audio::Effects,
+    encoding: &audio::EncodingSettings,
+) -> anyhow::Result<CachedSpeech> {
+    let mut decoded = match audio::decode(audio_file.clone()) {
+        Ok(decoded) => decoded,
+        // Without decoding, the audio can only be served when nothing was requested that
+        // would change it.
+        Err(err)
+            if err.is::<audio::UnsupportedCodec>()
+                && args.passthrough_unsupported_codecs
+                && *effects == audio::Effects::default()
+                && *encoding == args.default_encoding(&request.response_format) =>
+        {
+            return Ok(CachedSpeech {
+                audio: audio_file.to_vec(),
+                perceptual_hash: None,
+            });
+        }
+        Err(err) => return Err(err),
+    };
+    if let Some(max_duration_ms) = args.max_output_duration_ms {
+        let truncate = args.overlong_output == OverlongOutputPolicy::Truncate;
+        audio::limit_duration(&mut decoded, max_duration_ms, truncate)?;
+    }
+    let perceptual_hash = args
+        .perceptual_hash
+        .then(|| audio::perceptual_hash(&decoded.samples));
+    audio::apply_effects(&mut decoded, effects)?;
+    Ok(CachedSpeech {
+        audio: audio::encode_mp3(&decoded, encoding)?,
+        perceptual_hash,
+    })
+}
+
+/// How the synthesized text is written to the logs, since it may contain sensitive data.
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum LogTextMode {
+    Full,
+    Truncated,
+    Hashed,
+    Omitted,
+}
+
+/// What happens to synthesized audio that is longer than `--max-output-duration-ms`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OverlongOutputPolicy {
+    /// Fail the request without caching anything.
+    Reject,
+    /// Cut off the audio at the maximum duration.
+    Truncate,
+}
+
+/// How query parameters that are passed more than once are handled.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum DuplicateParamPolicy {
+    /// Respond with `400 Bad Request`.
+    Reject,
+    /// Use the first occurrence.
+    First,
+    /// Use the last occurrence.
+    Last,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(version, about)]
+struct Args {
+    #[arg(long, default_value = "0.0.0.0")]
+    host: String,
+
+    #[arg(long, default_value = "9001")]
+    port: u16,
+
+    /// Address to listen on, can be repeated. Replaces `--host` and `--port` when given.
+    #[arg(long)]
+    listen: Vec<std::net::SocketAddr>,
+
+    /// Formats for which only the base audio is cached and the volume is applied per request.
+    #[arg(long = "serve-time-gain-format")]
+    serve_time_gain_formats: Vec<String>,
+
+    #[arg(long, value_enum, default_value = "hashed")]
+    log_text: LogTextMode,
+
+    /// Default number of peaks returned by the waveform endpoint.
+    #[arg(long, default_value = "64")]
+    waveform_buckets: usize,
+
+    /// Bitrate in kbps used for a format when the client does not specify one, e.g. `mp3=128`.
+    #[arg(long = "default-bitrate", value_parser = parse_named_setting::<u16>)]
+    default_bitrates: Vec<(String, u16)>,
+
+    /// LAME quality (0-9) used for a format when the client does not specify one, e.g. `mp3=2`.
+    #[arg(long = "default-quality", value_parser = parse_named_setting::<u8>)]
+    default_qualities: Vec<(String, u8)>,
+
+    /// Send audio with chunked transfer encoding instead of a fixed content length. Headers are
+    /// still only sent once the audio is ready, because the status and headers depend on the
+    /// result of the synthesis.
+    #[arg(long)]
+    chunked_responses: bool,
+
+    /// Stop adding cache entries while the available system memory is below this, e.g. `256 MiB`.
+    #[arg(long)]
+    min_available_memory: Option<byte_unit::Byte>,
+
+    /// Directory where upstream audio that cannot be decoded is stored for debugging.
+    #[arg(long)]
+    decode_failure_dir: Option<std::path::PathBuf>,
+
+    /// Maximum number of decode failures kept in the debug directory.
+    #[arg(long, default_value = "20")]
+    decode_failure_limit: usize,
+
+    /// Sample rate of all generated audio. By default the rate of the upstream audio is kept.
+    #[arg(long)]
+    output_sample_rate: Option<u32>,
+
+    /// Number of channels of all generated audio.
+    #[arg(long, default_value = "1")]
+    output_channels: u8,
+
+    /// Maximum number of phrases synthesized in the background because of prefetch hints.
+    #[arg(long, default_value = "2")]
+    max_concurrent_prefetches: usize,
+
+    /// Remove all ID3 tags from the served audio.
+    #[arg(long)]
+    strip_id3: bool,
+
+    /// Replace the ID3 tags of the served audio with a tag containing only this comment.
+    #[arg(long)]
+    id3_comment: Option<String>,
+
+    /// Seconds between checks whether the upstream API is reachable, as reported by `/ready`.
+    #[arg(long, default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+    upstream_check_interval: u64,
+
+    /// Give a voice its own cache of the given size, e.g. `echo=4 MiB`. This is in addition to
+    /// the cache that is shared by all other voices.
+    #[arg(long = "voice-cache-size", value_parser = parse_named_setting::<byte_unit::Byte>)]
+    voice_cache_sizes: Vec<(String, byte_unit::Byte)>,
+
+    /// Seconds a signed request stays valid, when a signing key is configured.
+    #[arg(long, default_value = "300")]
+    signature_max_age: u64,
+
+    /// Phrase that can be requested without a signature when all other parameters are left at
+    /// their defaults, e.g. `echo=Please wait`.
+    #[arg(long = "public-phrase", value_parser = parse_named_setting::<String>)]
+    public_phrases: Vec<(String, String)>,
+
+    /// How query parameters that are passed more than once are handled. Signatures are checked
+    /// against the query with the duplicates removed.
+    #[arg(long, value_enum, default_value = "reject")]
+    duplicate_params: DuplicateParamPolicy,
+
+    /// Check the first bytes of upstream responses and reject those that are JSON, HTML or empty
+    /// before decoding or caching them.
+    #[arg(long)]
+    sniff_upstream_audio: bool,
+
+    /// Enable parameters that help with developing clients, like `simulate_delay_ms`. Has no
+    /// effect in release builds.
+    #[arg(long)]
+    dev_mode: bool,
+
+    /// Voice used instead when the upstream API fails for the requested voice without producing
+    /// audio, i.e. it is unreachable or answers with an empty 5xx or 429 response. Responses
+    /// contain an `x-substituted-voice` header then.
+    #[arg(long)]
+    fallback_voice: Option<String>,
+
+    /// Remove zero-width characters and collapse whitespace in the text before synthesizing it.
+    #[arg(long)]
+    clean_text: bool,
+
+    /// Rhai script that transforms the text before it is synthesized. The script gets the
+    /// `text` variable and evaluates to the new text.
+    #[arg(long, value_parser = |path: &str| script::TextScript::load(path.as_ref()))]
+    text_script: Option<script::TextScript>,
+
+    /// Apply a limiter with this threshold to audio whose volume is raised, so that large
+    /// volume factors make it louder without clipping.
+    #[arg(long)]
+    limiter_threshold_dbfs: Option<f32>,
+
+    /// How long before a peak the limiter starts to lower the gain.
+    #[arg(long, default_value = "5")]
+    limiter_lookahead_ms: u32,
+
+    /// How long it takes for the limiter gain to recover after a peak.
+    #[arg(long, default_value = "100")]
+    limiter_release_ms: u32,
+
+    /// Maximum number of phrases in a sprite from `/speak/sprite`.
+    #[arg(long, default_value = "16")]
+    max_sprite_clips: usize,
+
+    /// Serve upstream audio with a codec that cannot be decoded as is, when no effects or
+    /// encoding settings are requested. Otherwise such requests fail.
+    #[arg(long)]
+    passthrough_unsupported_codecs: bool,
+
+    /// How long the result of an upstream request is shared with requests for the same speech
+    /// that arrive after it finished. Requests arriving while it is in progress always share it.
+    #[arg(long, default_value = "0")]
+    coalesce_window_ms: u64,
+
+    /// Add an `x-audio-perceptual-hash` header that only changes when the synthesized speech
+    /// sounds different, e.g. to detect changes after regenerating audio. It is computed before
+    /// effects are applied.
+    #[arg(long)]
+    perceptual_hash: bool,
+
+    /// Upper limit for the duration of synthesized audio, as a safety net against runaway
+    /// generations.
+    #[arg(long)]
+    max_output_duration_ms: Option<u64>,
+
+    /// How audio longer than `--max-output-duration-ms` is handled.
+    #[arg(long, value_enum, default_value = "reject")]
+    overlong_output: OverlongOutputPolicy,
+
+    /// Speech endpoint of an OpenAI compatible API.
+    #[arg(long, default_value = OPENAI_SPEECH_URL)]
+    upstream_url: String,
+
+    /// Endpoint that is requested to check whether the upstream API is reachable. Defaults to
+    /// the models endpoint next to `--upstream-url`.
+    #[arg(long)]
+    upstream_check_url: Option<String>,
+}
+
+impl Args {
+    /// Some formats are cheap enough to process that only the base audio is cached and the gain
+    /// and other effects are applied for every request. This avoids storing a separate copy per
+    /// volume level.
+    fn applies_effects_at_serve_time(&self, format: &str) -> bool {
+        self.serve_time_gain_formats
+            .iter()
+            .any(|name| name == format)
+    }
+
+    fn limiter(&self) -> Option<audio::LimiterSettings> {
+        Some(audio::LimiterSettings {
+            threshold_dbfs: ordered_float::NotNan::new(self.limiter_threshold_dbfs?).ok()?,
+            lookahead_ms: self.limiter_lookahead_ms,
+            release_ms: self.limiter_release_ms,
+        })
+    }
+
+    fn upstream_check_url(&self) -> String {
+        if let Some(url) = &self.upstream_check_url {
+            return url.clone();
+        }
+        match self.upstream_url.strip_suffix("/audio/speech") {
+            Some(base) => format!("{}/models", base),
+            None => self.upstream_url.clone(),
+        }
+    }
+
+    fn default_encoding(&self, format: &str) -> audio::EncodingSettings {
+        audio::EncodingSettings {
+            bitrate_kbps: named_setting(&self.default_bitrates, format).unwrap_or(192),
+            quality: named_setting(&self.default_qualities, format).unwrap_or(0),
+            sample_rate: self.output_sample_rate,
+            channels: self.output_channels,
+        }
+    }
+}
+
+/// Binds all `--listen` addresses, or `--host` and `--port` when there are none.
+fn bind_listeners(args: &Args) -> Vec<TcpListener> {
+    if args.listen.is_empty() {
+        let listener =
+            TcpListener::bind((args.host.clone(), args.port)).expect("Cannot bind to port");
+        let actual_port = listener.local_addr().unwrap().port();
+        println!("Start server on http://{}:{}", args.host, actual_port);
+        vec![listener]
+    } else {
+        args.listen
+            .iter()
+            .map(|address| {
+                let listener = TcpListener::bind(address).expect("Cannot bind to address");
+                println!("Start server on http://{}", listener.local_addr().unwrap());
+                listener
+            })
+            .collect()
+    }
+}
+
+/// Rewrites the query string so that every parameter occurs only once. Parameters are kept in
+/// their order and spelling, so a query without duplicates stays exactly the same. Rejected
+/// duplicates are left in place, parsing the parameters fails on them.
+fn deduplicate_query(req: &mut actix_web::dev::ServiceRequest, policy: DuplicateParamPolicy) {
+    if policy == DuplicateParamPolicy::Reject {
+        return;
+    }
+    let query = req.query_string();
+    let pairs: Vec<_> = query.split('&').collect();
+    let key = |pair: &str| {
+        let key = pair.split('=').next().unwrap_or_default().replace('+', " ");
+        percent_encoding::percent_decode_str(&key)
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+    let keys: Vec<_> = pairs.iter().map(|pair| key(pair)).collect();
+    let kept_pairs: Vec<_> = pairs
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| match policy {
+            DuplicateParamPolicy::First => !keys[..i].contains(&keys[i]),
+            _ => !keys[i + 1..].contains(&keys[i]),
+        })
+        .map(|(_, pair)| *pair)
+        .collect();
+    if kept_pairs.len() == pairs.len() {
+        return;
+    }
+    let uri = format!("{}?{}", req.path(), kept_pairs.join("&"));
+    req.head_mut().uri = uri.parse().expect("Query was part of a valid URI before");
+}
+
+fn named_setting<T: Copy>(settings: &[(String, T)], name: &str) -> Option<T> {
+    settings
+        .iter()
+        .rev()
+        .find(|(setting_name, _)| setting_name == name)
+        .map(|(_, value)| *value)
+}
+
+fn parse_named_setting<T: std::str::FromStr>(value: &str) -> Result<(String, T), String> {
+    let (name, setting) = value
+        .split_once('=')
+        .ok_or("expected <NAME>=<VALUE>".to_string())?;
+    let setting = setting
+        .parse()
+        .map_err(|_| format!("invalid value: {}", setting))?;
+    Ok((name.to_string(), setting))
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+    if let Err(err) = args.default_encoding("mp3").validate() {
+        panic!("Invalid encoding settings: {}", err);
+    }
+    if let Some(Err(err)) = args.limiter().map(|limiter| limiter.validate()) {
+        panic!("Invalid limiter settings: {}", err);
+    }
+
+    let listeners = bind_listeners(&args);
+
+    let secrets = load_secrets(
+        "secrets.toml",
+        std::env::var("OPENAI_API_KEY").ok(),
+        std::env::var("SPEECH_CACHE_SIGNING_KEY").ok(),
+    )
+    .expect("OpenAI key must be set in secrets.toml or the OPENAI_API_KEY environment variable");
+    let shared = Arc::new(Mutex::new(SharedState::new(&args)));
+
+    actix_web::rt::spawn(check_upstream_periodically(
+        args.upstream_check_url(),
+        secrets.clone(),
+        shared.clone(),
+        std::time::Duration::from_secs(args.upstream_check_interval),
+    ));
+
+    let prefetch_permits = Arc::new(tokio::sync::Semaphore::new(args.max_concurrent_prefetches));
+
+    let mut server = HttpServer::new(move || {
+        let duplicate_params = args.duplicate_params;
+        actix_web::App::new()
+            .app_data(actix_web::web::Data::new(AppState {
                 secrets: secrets.clone(),
+                args: args.clone(),
+                prefetch_permits: prefetch_permits.clone(),
                 shared: shared.clone(),
+                available_memory,
             }))
             .service(get_index)
+            .service(get_ready)
             .service(get_speech)
+            .service(get_speech_waveform)
+            .service(get_speech_sprite)
+            .wrap_fn(move |mut req, srv| {
+                deduplicate_query(&mut req, duplicate_params);
+                srv.call(req)
+            })
             .wrap(actix_cors::Cors::permissive())
     })
-    .workers(1)
-    .listen(listener)?
-    .run()
-    .await
+    .workers(1);
+    for listener in listeners {
+        server = server.listen(listener)?;
+    }
+    server.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_args(args: &[&str]) -> Args {
+        Args::parse_from(std::iter::once("speech-cache").chain(args.iter().copied()))
+    }
+
+    fn resolve_query(args: &Args, query: &str) -> ResolvedRequest {
+        let info = actix_web::web::Query::<SpeechRequestParams>::from_query(query).unwrap();
+        resolve_request(args, &info)
+    }
+
+    /// A short tone whose pitch depends on `seed`, so that different texts sound different.
+    fn test_tone(seed: usize, sample_rate: u32) -> audio::DecodedAudio {
+        let frequency = 200.0 + 50.0 * (seed % 16) as f32;
+        audio::DecodedAudio {
+            samples: (0..sample_rate / 2)
+                .map(|i| {
+                    0.5 * (std::f32::consts::TAU * frequency * i as f32 / sample_rate as f32).sin()
+                })
+                .collect(),
+            right_channel: None,
+            sample_rate,
+        }
+    }
+
+    fn test_mp3(seed: usize) -> Vec<u8> {
+        test_mp3_at(seed, UPSTREAM_SAMPLE_RATE)
+    }
+
+    fn test_mp3_at(seed: usize, sample_rate: u32) -> Vec<u8> {
+        let settings = audio::EncodingSettings {
+            bitrate_kbps: 64,
+            quality: 2,
+            sample_rate: None,
+            channels: 1,
+        };
+        audio::encode_mp3(&test_tone(seed, sample_rate), &settings).unwrap()
+    }
+
+    /// Fake speech API that counts its requests.
+    struct MockUpstream {
+        url: String,
+        requests: Arc<std::sync::atomic::AtomicUsize>,
+        /// Stops the server to simulate an outage.
+        server: Option<actix_web::dev::ServerHandle>,
+    }
+
+    impl MockUpstream {
+        fn requests(&self) -> usize {
+            self.requests.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    /// Answers with a tone for the text after `delay`. Requests for the `unavailable` voice
+    /// fail temporarily without producing audio, the `unauthorized` voice is rejected, the
+    /// `json` voice gets an error with a success status, the `opus` voice gets audio that cannot
+    /// be decoded and the `wideband` voice gets audio at 48 kHz. The models endpoint, which is
+    /// used to check reachability, is not counted.
+    fn start_mock_upstream(delay: std::time::Duration) -> MockUpstream {
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = requests.clone();
+        let server = HttpServer::new(move || {
+            let counter = counter.clone();
+            actix_web::App::new()
+                .route("/v1/models", actix_web::web::get().to(HttpResponse::Ok))
+                .default_service(actix_web::web::to(
+                    move |request: actix_web::web::Json<serde_json::Value>| {
+                        counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        async move {
+                            actix_web::rt::time::sleep(delay).await;
+                            if request["voice"] == "unavailable" {
+                                return HttpResponse::ServiceUnavailable().finish();
+                            }
+                            if request["voice"] == "unauthorized" {
+                                return HttpResponse::Unauthorized().body("invalid key");
+                            }
+                            if request["voice"] == "json" {
+                                return HttpResponse::Ok().json(serde_json::json!({"error": {}}));
+                            }
+                            if request["voice"] == "opus" {
+                                return HttpResponse::Ok().body(audio::opus_in_ogg());
+                            }
+                            let input = request["input"].as_str().unwrap_or_default();
+                            HttpResponse::Ok().body(test_mp3(input.len()))
+                        }
+                    },
+                ))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/v1/audio/speech", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        MockUpstream {
+            url,
+            requests,
+            server: Some(handle),
+        }
+    }
+
+    fn test_state(args: &[&str], upstream: &MockUpstream) -> actix_web::web::Data<AppState> {
+        signed_test_state(args, upstream, None)
+    }
+
+    fn signed_test_state(
+        args: &[&str],
+        upstream: &MockUpstream,
+        signing_key: Option<&str>,
+    ) -> actix_web::web::Data<AppState> {
+        let args = test_args(&[args, &["--upstream-url", &upstream.url]].concat());
+        actix_web::web::Data::new(AppState {
+            secrets: Secrets {
+                openai_key: "test".to_string(),
+                signing_key: signing_key.map(str::to_string),
+            },
+            prefetch_permits: Arc::new(tokio::sync::Semaphore::new(args.max_concurrent_prefetches)),
+            shared: Arc::new(Mutex::new(SharedState::new(&args))),
+            // Independent of the machine that runs the tests.
+            available_memory: || Some(64 * 1024 * 1024),
+            args,
+        })
+    }
+
+    async fn send_request(
+        state: &actix_web::web::Data<AppState>,
+        uri: &str,
+    ) -> actix_web::dev::ServiceResponse {
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(state.clone())
+                .service(get_ready)
+                .service(get_speech)
+                .service(get_speech_waveform)
+                .service(get_speech_sprite),
+        )
+        .await;
+        let request = actix_web::test::TestRequest::get().uri(uri).to_request();
+        actix_web::test::call_service(&app, request).await
+    }
+
+    async fn audio_peak(response: actix_web::dev::ServiceResponse) -> f32 {
+        assert_eq!(response.status(), 200);
+        let body = actix_web::test::read_body(response).await;
+        audio::peak_amplitude(&audio::decode(body).unwrap().samples)
+    }
+
+    #[test]
+    fn secrets_are_read_from_the_environment_without_a_file() {
+        let missing_file = std::env::temp_dir().join("speech-cache-missing-secrets.toml");
+        let missing_file = missing_file.to_str().unwrap();
+        let secrets = load_secrets(missing_file, Some("from-env".to_string()), None).unwrap();
+        assert_eq!(secrets.openai_key, "from-env");
+        assert_eq!(secrets.signing_key, None);
+        assert!(load_secrets(missing_file, None, None).is_err());
+    }
+
+    #[test]
+    fn environment_overrides_the_secrets_file() {
+        let file =
+            std::env::temp_dir().join(format!("speech-cache-secrets-{}.toml", std::process::id()));
+        std::fs::write(
+            &file,
+            "openai_key = \"from-file\"\nsigning_key = \"file\"\n",
+        )
+        .unwrap();
+        let file_name = file.to_str().unwrap();
+        let from_file = load_secrets(file_name, None, None).unwrap();
+        let from_env = load_secrets(file_name, Some("from-env".to_string()), None).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(from_file.openai_key, "from-file");
+        assert_eq!(from_env.openai_key, "from-env");
+        assert_eq!(from_env.signing_key.as_deref(), Some("file"));
+    }
+
+    #[actix_web::test]
+    async fn serve_time_gain_shares_the_base_audio() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&["--serve-time-gain-format", "mp3"], &upstream);
+        let loud = audio_peak(send_request(&state, "/speak?text=Hello").await).await;
+        let quiet = audio_peak(send_request(&state, "/speak?text=Hello&volume=0.5").await).await;
+        assert_eq!(upstream.requests(), 1);
+        assert!((quiet / loud - 0.5).abs() < 0.05, "{} {}", loud, quiet);
+    }
+
+    #[test]
+    fn explicit_defaults_resolve_to_the_same_key() {
+        let args = test_args(&[]);
+        let implicit = resolve_query(&args, "text=Hello").cache_key;
+        let explicit = resolve_query(
+            &args,
+            "text=Hello&voice=echo&volume=1.0&bitrate=192&quality=0&loop_ready=false\
+             &stereo_width=0&truncate=false&low_bandwidth=false&beep_markers=",
+        )
+        .cache_key;
+        assert_eq!(implicit, explicit);
+    }
+
+    #[test]
+    fn parameter_order_does_not_change_the_key() {
+        let args = test_args(&[]);
+        assert_eq!(
+            resolve_query(&args, "text=Hello&voice=nova&volume=0.5&bitrate=64").cache_key,
+            resolve_query(&args, "bitrate=64&volume=0.5&voice=nova&text=Hello").cache_key,
+        );
+    }
+
+    #[test]
+    fn beep_marker_order_does_not_change_the_key() {
+        let args = test_args(&[]);
+        assert_eq!(
+            resolve_query(&args, "text=Hello&beep_markers=300,100,200").cache_key,
+            resolve_query(&args, "text=Hello&beep_markers=100,200,300").cache_key,
+        );
+    }
+
+    #[test]
+    fn different_audio_resolves_to_different_keys() {
+        let args = test_args(&[]);
+        let base = resolve_query(&args, "text=Hello").cache_key;
+        for query in [
+            "text=Hello!",
+            "text=Hello&voice=nova",
+            "text=Hello&volume=0.5",
+            "text=Hello&bitrate=64",
+            "text=Hello&seed=1",
+            "text=Hello&beep_markers=100",
+        ] {
+            assert_ne!(base, resolve_query(&args, query).cache_key, "{}", query);
+        }
+    }
+
+    #[test]
+    fn filter_cutoffs_are_validated_before_synthesis() {
+        let args = test_args(&[]);
+        let validate = |query| validate_request(&resolve_query(&args, query).cache_key).is_ok();
+        assert!(validate("text=Hello&lowpass_hz=8000"));
+        assert!(!validate("text=Hello&lowpass_hz=12000"));
+        assert!(!validate("text=Hello&highpass_hz=9000&low_bandwidth=true"));
+    }
+
+    #[test]
+    fn log_text_modes() {
+        let text = "The quick brown fox jumps over the lazy dog";
+        assert_eq!(text_for_log(text, LogTextMode::Full), format!("{:?}", text));
+        assert_eq!(
+            text_for_log(text, LogTextMode::Truncated),
+            "\"The quick brown \"..."
+        );
+        assert_eq!(text_for_log("Short", LogTextMode::Truncated), "\"Short\"");
+        let hashed = text_for_log(text, LogTextMode::Hashed);
+        assert!(!hashed.contains("quick"));
+        assert_eq!(hashed, text_for_log(text, LogTextMode::Hashed));
+        assert_ne!(hashed, text_for_log("Other text", LogTextMode::Hashed));
+        assert_eq!(text_for_log(text, LogTextMode::Omitted), "<omitted>");
+    }
+
+    #[actix_web::test]
+    async fn waveform_endpoint() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&[], &upstream);
+        let response = send_request(&state, "/speak/waveform?text=Hello&buckets=32").await;
+        assert_eq!(response.status(), 200);
+        let peaks: Vec<f32> = actix_web::test::read_body_json(response).await;
+        assert_eq!(peaks.len(), 32);
+        assert!(peaks.iter().any(|peak| *peak > 0.4));
+        let response = send_request(&state, "/speak/waveform?text=Hello&buckets=0").await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[test]
+    fn per_format_encoding_defaults() {
+        let args = test_args(&["--default-bitrate", "mp3=128", "--default-quality", "mp3=5"]);
+        let encoding = args.default_encoding("mp3");
+        assert_eq!((encoding.bitrate_kbps, encoding.quality), (128, 5));
+        let other = args.default_encoding("opus");
+        assert_eq!((other.bitrate_kbps, other.quality), (192, 0));
+
+        let key = resolve_query(&args, "text=Hello").cache_key;
+        assert_eq!(key.encoding, encoding);
+        let key = resolve_query(&args, "text=Hello&bitrate=64&quality=2").cache_key;
+        assert_eq!((key.encoding.bitrate_kbps, key.encoding.quality), (64, 2));
+    }
+
+    #[test]
+    fn truncated_encoder_output_is_not_served() {
+        let err = audio::TruncatedEncoderOutput {
+            input_samples: 24000,
+            output_bytes: 0,
+        };
+        let response = processed_or_fallback(Err(err.into()), b"unprocessed").unwrap_err();
+        assert_eq!(response.status(), 500);
+    }
+
+    #[actix_web::test]
+    async fn chunked_responses_stream_the_same_audio() {
+        use actix_web::body::{BodySize, MessageBody};
+
+        let audio: Vec<u8> = (0..40000).map(|i| i as u8).collect();
+        let response = audio_response(&test_args(&[]), audio.clone());
+        assert_eq!(response.body().size(), BodySize::Sized(40000));
+
+        let response = audio_response(&test_args(&["--chunked-responses"]), audio.clone());
+        assert_eq!(response.body().size(), BodySize::Stream);
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(body, audio);
+    }
+
+    #[test]
+    fn parses_available_memory() {
+        let meminfo = "MemTotal:       16314456 kB\nMemFree:         1199012 kB\n\
+                       MemAvailable:    9245020 kB\nBuffers:          482036 kB\n";
+        assert_eq!(parse_available_memory(meminfo), Some(9245020 * 1024));
+        assert_eq!(parse_available_memory("MemTotal: 16314456 kB\n"), None);
+    }
+
+    #[actix_web::test]
+    async fn caching_pauses_when_memory_is_low() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        // Test states report 64 MiB of available memory.
+        let state = test_state(&["--min-available-memory", "128 MiB"], &upstream);
+        send_request(&state, "/speak?text=Hello").await;
+        send_request(&state, "/speak?text=Hello").await;
+        assert_eq!(upstream.requests(), 2);
+
+        let state = test_state(&["--min-available-memory", "32 MiB"], &upstream);
+        send_request(&state, "/speak?text=Hello").await;
+        send_request(&state, "/speak?text=Hello").await;
+        assert_eq!(upstream.requests(), 3);
+    }
+
+    #[actix_web::test]
+    async fn binds_every_listen_address() {
+        let args = test_args(&["--listen", "127.0.0.1:0", "--listen", "127.0.0.1:0"]);
+        let listeners = bind_listeners(&args);
+        assert_eq!(listeners.len(), 2);
+        let addresses: Vec<_> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        assert_ne!(addresses[0], addresses[1]);
+
+        let mut server = HttpServer::new(|| actix_web::App::new().service(get_index)).workers(1);
+        for listener in listeners {
+            server = server.listen(listener).unwrap();
+        }
+        actix_web::rt::spawn(server.run());
+        for address in addresses {
+            let response = reqwest::get(format!("http://{}/", address)).await.unwrap();
+            assert_eq!(response.status(), 200, "{}", address);
+        }
+
+        let listeners = bind_listeners(&test_args(&["--host", "127.0.0.1", "--port", "0"]));
+        assert_eq!(listeners.len(), 1);
+    }
+
+    #[test]
+    fn keeps_only_the_latest_decode_failures() {
+        let directory =
+            std::env::temp_dir().join(format!("speech-cache-failures-{}", std::process::id()));
+        let args = test_args(&[
+            "--decode-failure-dir",
+            directory.to_str().unwrap(),
+            "--decode-failure-limit",
+            "2",
+        ]);
+        let request = resolve_query(&args, "text=Hello").cache_key.request;
+        let err = anyhow::anyhow!("cannot decode");
+        for audio_file in [b"first", b"secnd", b"third"] {
+            write_decode_failure(&args, &request, audio_file, &err).unwrap();
+        }
+        let mut files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        let metadata = std::fs::read_to_string(&files[1]).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(files.len(), 4);
+        assert!(metadata.contains("cannot decode"));
+    }
+
+    #[actix_web::test]
+    async fn rejected_beep_markers_do_not_synthesize_again() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&[], &upstream);
+        let uri = "/speak?text=Hello&beep_markers=100,5000";
+        assert_eq!(send_request(&state, uri).await.status(), 400);
+        assert_eq!(send_request(&state, uri).await.status(), 400);
+        let response = send_request(&state, "/speak?text=Hello&beep_markers=100").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(upstream.requests(), 1);
+    }
+
+    #[actix_web::test]
+    async fn output_sample_rate_and_channels_can_be_forced() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(
+            &["--output-sample-rate", "16000", "--output-channels", "2"],
+            &upstream,
+        );
+        let encoding = state.args.default_encoding("mp3");
+        assert_eq!((encoding.sample_rate, encoding.channels), (Some(16000), 2));
+        for voice in ["echo", "wideband"] {
+            let uri = format!("/speak?text=Hello&voice={}", voice);
+            let response = send_request(&state, &uri).await;
+            assert_eq!(response.status(), 200);
+            let body = actix_web::test::read_body(response).await;
+            assert_eq!(audio::decode(body).unwrap().sample_rate, 16000, "{}", voice);
+        }
+    }
+
+    #[actix_web::test]
+    async fn explain_describes_the_request_without_synthesizing() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&[], &upstream);
+        let uri = "/speak?text=Hello&volume=0.5&explain=true";
+        let explanation: serde_json::Value =
+            actix_web::test::read_body_json(send_request(&state, uri).await).await;
+        assert_eq!(explanation["cached"], false);
+        assert_eq!(explanation["resolved"]["request"]["voice"], "echo");
+        assert_eq!(explanation["upstream"]["url"], upstream.url);
+        assert_eq!(explanation["effects_chain"][0], "gain x0.5");
+        assert_eq!(upstream.requests(), 0);
+
+        send_request(&state, "/speak?text=Hello&volume=0.5").await;
+        let explanation: serde_json::Value =
+            actix_web::test::read_body_json(send_request(&state, uri).await).await;
+        assert_eq!(explanation["cached"], true);
+    }
+
+    #[test]
+    fn stereo_width_requests_stereo_output() {
+        let args = test_args(&[]);
+        let key = resolve_query(&args, "text=Hello&stereo_width=0.5").cache_key;
+        assert_eq!(key.encoding.channels, 2);
+        assert!(validate_request(&key).is_ok());
+        let key = resolve_query(&args, "text=Hello&stereo_width=0.5&low_bandwidth=true").cache_key;
+        assert!(validate_request(&key).is_err());
+        let key = resolve_query(&args, "text=Hello&stereo_width=2").cache_key;
+        assert!(validate_request(&key).is_err());
+    }
+
+    #[test]
+    fn truncates_at_a_word_boundary() {
+        let args = test_args(&[]);
+        let words = "word ".repeat(30);
+        let info = |query: &str| {
+            actix_web::web::Query::<SpeechRequestParams>::from_query(query)
+                .unwrap()
+                .into_inner()
+        };
+        let long = info(&format!("text={}&truncate=true", words.trim_end()));
+        let (text, truncated) = resolve_text(&args, &long);
+        assert!(truncated);
+        assert_eq!(text, "word ".repeat(20).trim_end());
+
+        let (text, truncated) = resolve_text(&args, &info("text=Short&truncate=true"));
+        assert_eq!((text.as_str(), truncated), ("Short", false));
+
+        let without_truncate = info(&format!("text={}", words.trim_end()));
+        assert!(!resolve_text(&args, &without_truncate).1);
+        let key = resolve_request(&args, &without_truncate).cache_key;
+        assert!(validate_request(&key).is_err());
+    }
+
+    #[actix_web::test]
+    async fn truncated_text_is_reported() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&[], &upstream);
+        let words = "word%20".repeat(30);
+        let response = send_request(&state, &format!("/speak?text={}&truncate=true", words)).await;
+        assert_eq!(response.status(), 200);
+        let header = response.headers().get("x-truncated-text").unwrap();
+        assert_eq!(header, &"word%20".repeat(20)[..7 * 20 - 3]);
+        let response = send_request(&state, "/speak?text=Hello&truncate=true").await;
+        assert!(response.headers().get("x-truncated-text").is_none());
+    }
+
+    #[actix_web::test]
+    async fn prefetches_hinted_phrases() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&[], &upstream);
+        let response = send_request(&state, "/speak?text=Hello&prefetch=%5B%22World%22%5D").await;
+        assert_eq!(response.status(), 200);
+        for _ in 0..100 {
+            if upstream.requests() == 2 && state.prefetch_permits.available_permits() == 2 {
+                break;
+            }
+            actix_web::rt::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(upstream.requests(), 2);
+        send_request(&state, "/speak?text=World").await;
+        assert_eq!(upstream.requests(), 2);
+
+        let response = send_request(&state, "/speak?text=Hello&prefetch=not-json").await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[test]
+    fn seed_is_only_sent_when_given() {
+        let args = test_args(&[]);
+        let request = resolve_query(&args, "text=Hello").cache_key.request;
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(json.get("seed").is_none());
+        let request = resolve_query(&args, "text=Hello&seed=7").cache_key.request;
+        assert_eq!(serde_json::to_value(&request).unwrap()["seed"], 7);
+        assert_ne!(
+            resolve_query(&args, "text=Hello&seed=7").cache_key,
+            resolve_query(&args, "text=Hello&seed=8").cache_key
+        );
+    }
+
+    #[test]
+    fn id3_settings_replace_the_tags() {
+        let mp3 = [audio::id3_comment_tag("upstream").as_slice(), &test_mp3(0)].concat();
+        let stripped = apply_id3_settings(&test_args(&["--strip-id3"]), mp3.clone());
+        assert_eq!(stripped, audio::strip_id3(&mp3));
+        let commented = apply_id3_settings(&test_args(&["--id3-comment", "cached"]), mp3.clone());
+        assert_eq!(
+            commented,
+            [
+                audio::id3_comment_tag("cached").as_slice(),
+                audio::strip_id3(&mp3)
+            ]
+            .concat()
+        );
+        assert_eq!(apply_id3_settings(&test_args(&[]), mp3.clone()), mp3);
+    }
+
+    #[actix_web::test]
+    async fn ready_reports_the_last_upstream_check() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&[], &upstream);
+        assert_eq!(
+            state.args.upstream_check_url(),
+            upstream.url.replace("audio/speech", "models")
+        );
+        assert_eq!(send_request(&state, "/ready").await.status(), 503);
+
+        let interval = std::time::Duration::from_millis(50);
+        actix_web::rt::spawn(check_upstream_periodically(
+            state.args.upstream_check_url(),
+            state.secrets.clone(),
+            state.shared.clone(),
+            interval,
+        ));
+        for reachable in [true, false] {
+            if !reachable {
+                upstream.server.as_ref().unwrap().stop(false).await;
+            }
+            actix_web::rt::time::sleep(interval * 4).await;
+            let response = send_request(&state, "/ready").await;
+            assert_eq!(response.status().is_success(), reachable);
+            let body: serde_json::Value = actix_web::test::read_body_json(response).await;
+            assert_eq!(body["upstream_reachable"], reachable);
+        }
+        assert_eq!(upstream.requests(), 0);
+    }
+
+    #[test]
+    fn voice_partitions_are_not_evicted_by_other_voices() {
+        let args = test_args(&["--voice-cache-size", "nova=64 KiB"]);
+        let mut cache = SpeechCache::new(64 * 1024, &args.voice_cache_sizes);
+        let speech = CachedSpeech {
+            audio: vec![0; 8 * 1024],
+            perceptual_hash: None,
+        };
+        let nova = resolve_query(&args, "text=Hello&voice=nova").cache_key;
+        cache.insert(nova.clone(), speech.clone());
+        for i in 0..20 {
+            let key = resolve_query(&args, &format!("text=Hello {}", i)).cache_key;
+            cache.insert(key, speech.clone());
+        }
+        assert!(cache.contains(&nova));
+        assert!(!cache.contains(&resolve_query(&args, "text=Hello 0").cache_key));
+        assert!(cache.contains(&resolve_query(&args, "text=Hello 19").cache_key));
+    }
+
+    #[actix_web::test]
+    async fn low_bandwidth_output_is_small_and_mono() {
+        let args = test_args(&["--output-channels", "2"]);
+        let key = resolve_query(&args, "text=Hello&low_bandwidth=true").cache_key;
+        assert_eq!(key.encoding, audio::EncodingSettings::LOW_BANDWIDTH);
+
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&[], &upstream);
+        let full = send_request(&state, "/speak?text=Hello").await;
+        let full = actix_web::test::read_body(full).await;
+        let small = send_request(&state, "/speak?text=Hello&low_bandwidth=true").await;
+        let small = actix_web::test::read_body(small).await;
+        assert!(small.len() * 4 < full.len());
+        assert_eq!(audio::decode(small).unwrap().sample_rate, 16000);
+    }
+
+    #[actix_web::test]
+    async fn signed_requests_are_required_with_a_signing_key() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = signed_test_state(&[], &upstream, Some("secret"));
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let query = format!("text=Hello&timestamp={}&nonce=abc", now);
+
+        let unsigned = send_request(&state, "/speak?text=Hello").await;
+        assert_eq!(unsigned.status(), 401);
+        let uri = format!("/speak?{}", signing::sign_query(b"secret", &query));
+        assert_eq!(send_request(&state, &uri).await.status(), 200);
+        assert_eq!(send_request(&state, &uri).await.status(), 401);
+        assert_eq!(upstream.requests(), 1);
+    }
+
+    #[actix_web::test]
+    async fn overlong_output_is_rejected_or_truncated() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&["--max-output-duration-ms", "200"], &upstream);
+        assert_eq!(
+            send_request(&state, "/speak?text=Hello").await.status(),
+            502
+        );
+        assert_eq!(
+            send_request(&state, "/speak?text=Hello").await.status(),
+            502
+        );
+        assert_eq!(upstream.requests(), 2);
+
+        let state = test_state(
+            &[
+                "--max-output-duration-ms",
+                "200",
+                "--overlong-output",
+                "truncate",
+            ],
+            &upstream,
+        );
+        let response = send_request(&state, "/speak?text=Hello").await;
+        assert_eq!(response.status(), 200);
+        let body = actix_web::test::read_body(response).await;
+        let decoded = audio::decode(body).unwrap();
+        let duration_ms = decoded.samples.len() as u32 * 1000 / decoded.sample_rate;
+        // The encoder adds some padding.
+        assert!(duration_ms < 300, "{}", duration_ms);
+    }
+
+    #[actix_web::test]
+    async fn public_phrases_only_skip_signing_with_default_parameters() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = signed_test_state(
+            &["--public-phrase", "echo=Please wait"],
+            &upstream,
+            Some("secret"),
+        );
+        let public = send_request(&state, "/speak?text=Please%20wait").await;
+        assert_eq!(public.status(), 200);
+        let explicit_voice = send_request(&state, "/speak?text=Please%20wait&voice=echo").await;
+        assert_eq!(explicit_voice.status(), 200);
+        for uri in [
+            "/speak?text=Please%20wait&seed=1",
+            "/speak?text=Please%20wait&volume=0.5",
+            "/speak?text=Please%20wait&bitrate=64",
+            "/speak?text=Please%20wait&voice=nova",
+            "/speak?text=Please%20wait!",
+        ] {
+            assert_eq!(send_request(&state, uri).await.status(), 401, "{}", uri);
+        }
+        assert_eq!(upstream.requests(), 1);
+    }
+
+    #[actix_web::test]
+    async fn duplicate_query_parameters() {
+        let query = "text=Hi&voice=nova&volume=2&vo%69ce=echo";
+        let deduplicated = |policy| {
+            let mut req = actix_web::test::TestRequest::get()
+                .uri(&format!("/speak?{}", query))
+                .to_srv_request();
+            deduplicate_query(&mut req, policy);
+            req.query_string().to_string()
+        };
+        assert_eq!(deduplicated(DuplicateParamPolicy::Reject), query);
+        assert_eq!(
+            deduplicated(DuplicateParamPolicy::First),
+            "text=Hi&voice=nova&volume=2"
+        );
+        assert_eq!(
+            deduplicated(DuplicateParamPolicy::Last),
+            "text=Hi&volume=2&vo%69ce=echo"
+        );
+
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&[], &upstream);
+        let response = send_request(&state, &format!("/speak?{}", query)).await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn non_audio_upstream_responses_are_rejected() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&["--sniff-upstream-audio"], &upstream);
+        let response = send_request(&state, "/speak?text=Hello&voice=json").await;
+        assert_eq!(response.status(), 502);
+        let body = actix_web::test::read_body(response).await;
+        assert_eq!(body, "upstream returned JSON instead of audio");
+        let response = send_request(&state, "/speak?text=Hello").await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn delays_are_only_simulated_in_dev_mode() {
+        let info = DelayRequestParams {
+            simulate_delay_ms: Some(100),
+        };
+        let start = std::time::Instant::now();
+        simulate_delay(&test_args(&[]), &info).await;
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+
+        let start = std::time::Instant::now();
+        simulate_delay(&test_args(&["--dev-mode"]), &info).await;
+        assert_eq!(
+            start.elapsed() >= std::time::Duration::from_millis(100),
+            cfg!(debug_assertions)
+        );
+    }
+
+    #[actix_web::test]
+    async fn falls_back_to_another_voice_when_the_voice_is_unavailable() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&[], &upstream);
+        let response = send_request(&state, "/speak?text=Hello&voice=unavailable").await;
+        assert_eq!(response.status(), 500);
+
+        let state = test_state(&["--fallback-voice", "echo"], &upstream);
+        let response = send_request(&state, "/speak?text=Hello&voice=unavailable").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get("x-substituted-voice").unwrap(),
+            "echo"
+        );
+        assert_eq!(upstream.requests(), 3);
+        // The fallback audio is cached for the fallback voice only.
+        let response = send_request(&state, "/speak?text=Hello&voice=echo").await;
+        assert!(response.headers().get("x-substituted-voice").is_none());
+        assert_eq!(upstream.requests(), 3);
+
+        let response = send_request(&state, "/speak?text=Hello&voice=unauthorized").await;
+        assert_eq!(response.status(), 500);
+        assert_eq!(upstream.requests(), 4);
+    }
+
+    #[test]
+    fn unavailable_encoder_is_a_temporary_failure() {
+        let err = anyhow::Error::from(audio::EncoderUnavailable);
+        let response = processed_or_fallback(Err(err), b"unprocessed").unwrap_err();
+        assert_eq!(response.status(), 503);
+    }
+
+    #[test]
+    fn cleans_invisible_characters_and_whitespace() {
+        let text = " Hello\u{00A0}\u{00A0}wor\u{200B}ld\t\n again\u{FEFF} ";
+        assert_eq!(
+            clean_text(&test_args(&["--clean-text"]), text),
+            "Hello world again"
+        );
+        assert_eq!(clean_text(&test_args(&[]), text), text);
+        assert_eq!(clean_text(&test_args(&["--clean-text"]), "\u{200B} "), "");
+    }
+
+    #[test]
+    fn text_script_runs_before_truncation() {
+        let path = std::env::temp_dir().join(format!("speech-cache-{}.rhai", std::process::id()));
+        std::fs::write(&path, r#"text.replace("&", "and "); text"#).unwrap();
+        let args = test_args(&["--text-script", path.to_str().unwrap()]);
+        std::fs::remove_file(&path).unwrap();
+
+        let resolved = resolve_query(&args, "text=Salt%20%26%20pepper");
+        assert_eq!(resolved.cache_key.request.input, "Salt and  pepper");
+        let text = format!("text={}&truncate=true", "%26".repeat(30));
+        let resolved = resolve_query(&args, &text);
+        assert!(resolved.truncated_text);
+        assert!(resolved.cache_key.request.input.starts_with("and and"));
+    }
+
+    #[test]
+    fn limiter_is_only_used_for_boosted_audio() {
+        let args = test_args(&["--limiter-threshold-dbfs=-1"]);
+        let boosted = resolve_query(&args, "text=Hello&volume=3").cache_key;
+        assert_eq!(boosted.effects.limiter, args.limiter());
+        assert!(boosted.effects.limiter.is_some());
+        let quiet = resolve_query(&args, "text=Hello&volume=0.5").cache_key;
+        assert_eq!(quiet.effects.limiter, None);
+        let boosted = resolve_query(&test_args(&[]), "text=Hello&volume=3").cache_key;
+        assert_eq!(boosted.effects.limiter, None);
+    }
+
+    #[actix_web::test]
+    async fn sprites_list_the_range_of_every_phrase() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&["--max-sprite-clips", "2"], &upstream);
+        let response = send_request(&state, "/speak/sprite?phrases=%5B%22A%22,%22BB%22%5D").await;
+        assert_eq!(response.status(), 200);
+        let clips: serde_json::Value =
+            serde_json::from_slice(response.headers().get("x-sprite-clips").unwrap().as_bytes())
+                .unwrap();
+        let sprite = actix_web::test::read_body(response).await;
+        for (i, text) in ["A", "BB"].iter().enumerate() {
+            let clip = send_request(&state, &format!("/speak?text={}", text)).await;
+            let clip = actix_web::test::read_body(clip).await;
+            let start = clips[i]["start"].as_u64().unwrap() as usize;
+            let end = clips[i]["end"].as_u64().unwrap() as usize;
+            assert_eq!(sprite[start..end], *audio::strip_id3(&clip));
+        }
+        assert_eq!(upstream.requests(), 2);
+
+        let response = send_request(&state, "/speak/sprite?phrases=%5B%5D").await;
+        assert_eq!(response.status(), 400);
+        let too_many = "/speak/sprite?phrases=%5B%22A%22,%22B%22,%22C%22%5D";
+        assert_eq!(send_request(&state, too_many).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn sprites_are_only_public_when_all_phrases_are() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let args = ["--public-phrase", "echo=Hi", "--public-phrase", "echo=Bye"];
+        let state = signed_test_state(&args, &upstream, Some("secret"));
+        let public = "/speak/sprite?phrases=%5B%22Hi%22,%22Bye%22%5D";
+        assert_eq!(send_request(&state, public).await.status(), 200);
+        let mixed = "/speak/sprite?text=Hi&phrases=%5B%22Hi%22,%22Secret%22%5D";
+        assert_eq!(send_request(&state, mixed).await.status(), 401);
+        let private = "/speak/sprite?text=Hi&phrases=%5B%22Secret%22%5D";
+        assert_eq!(send_request(&state, private).await.status(), 401);
+        assert_eq!(upstream.requests(), 2);
+    }
+
+    #[actix_web::test]
+    async fn client_cache_policy_only_changes_the_header() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&[], &upstream);
+        let cache_control = |response: &actix_web::dev::ServiceResponse| {
+            response
+                .headers()
+                .get(actix_web::http::header::CACHE_CONTROL)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        let response = send_request(&state, "/speak?text=Hello").await;
+        assert_eq!(cache_control(&response), "max-age=604800");
+        let response = send_request(&state, "/speak?text=Hello&client_cache=no-store").await;
+        assert_eq!(cache_control(&response), "no-store");
+        let response = send_request(&state, "/speak?text=Hello&client_cache=private").await;
+        assert_eq!(cache_control(&response), "private, max-age=604800");
+        assert_eq!(upstream.requests(), 1);
+        let response = send_request(&state, "/speak?text=Hello&client_cache=forever").await;
+        assert_eq!(response.status(), 400);
+    }
+
+    /// Fake speech API that sends only the start of the audio and then closes the connection.
+    fn start_truncating_upstream() -> MockUpstream {
+        use std::io::{Read, Write};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1/audio/speech", listener.local_addr().unwrap());
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().map_while(Result::ok) {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"}") {
+                    let Ok(read @ 1..) = stream.read(&mut buffer) else {
+                        break;
+                    };
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                let mut response = b"HTTP/1.1 200 OK\r\nContent-Length: 10000\r\n\r\n".to_vec();
+                response.extend_from_slice(&test_mp3(0)[..1000]);
+                let _ = stream.write_all(&response);
+            }
+        });
+        MockUpstream {
+            url,
+            requests,
+            server: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn only_retries_when_upstream_produced_no_audio() {
+        let closed_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let unreachable = MockUpstream {
+            url: format!("http://{}/v1/audio/speech", closed_port),
+            requests: Default::default(),
+            server: None,
+        };
+        let state = test_state(&[], &unreachable);
+        let request = resolve_query(&state.args, "text=Hello").cache_key.request;
+        let err = fetch_upstream_audio(&state, &request, "Hello")
+            .await
+            .unwrap_err();
+        assert!(err.no_audio_produced);
+
+        let upstream = start_truncating_upstream();
+        let state = test_state(&[], &upstream);
+        let err = fetch_upstream_audio(&state, &request, "Hello")
+            .await
+            .unwrap_err();
+        assert!(!err.no_audio_produced);
+        assert_eq!(err.status, 502);
+
+        let state = test_state(&["--fallback-voice", "nova"], &upstream);
+        let response = send_request(&state, "/speak?text=Hello").await;
+        assert_eq!(response.status(), 502);
+        assert_eq!(upstream.requests(), 2);
+    }
+
+    #[actix_web::test]
+    async fn unsupported_codecs_are_only_passed_through_unchanged() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&["--sniff-upstream-audio"], &upstream);
+        let response = send_request(&state, "/speak?text=Hello&voice=opus").await;
+        assert_eq!(response.status(), 502);
+        let body = actix_web::test::read_body(response).await;
+        assert!(!String::from_utf8_lossy(&body).contains("instead of audio"));
+
+        let args = [
+            "--passthrough-unsupported-codecs",
+            "--sniff-upstream-audio",
+            "--id3-comment",
+            "cached",
+        ];
+        let state = test_state(&args, &upstream);
+        let response = send_request(&state, "/speak?text=Hello&voice=opus").await;
+        assert_eq!(response.status(), 200);
+        let content_type = response.headers().get("content-type").unwrap();
+        assert_eq!(content_type, "audio/ogg");
+        let body = actix_web::test::read_body(response).await;
+        assert_eq!(body, audio::opus_in_ogg());
+        for query in ["volume=0.5", "low_bandwidth=true", "bitrate=64"] {
+            let uri = format!("/speak?text=Hello&voice=opus&{}", query);
+            assert_eq!(send_request(&state, &uri).await.status(), 502, "{}", query);
+        }
+    }
+
+    #[actix_web::test]
+    async fn concurrent_requests_share_the_upstream_request() {
+        let upstream = start_mock_upstream(std::time::Duration::from_millis(200));
+        let state = test_state(&[], &upstream);
+        let responses = futures_util::future::join_all(
+            ["1", "0.5", "0.25"]
+                .map(|volume| format!("/speak?text=Hello&volume={}", volume))
+                .iter()
+                .map(|uri| send_request(&state, uri)),
+        )
+        .await;
+        assert!(responses.iter().all(|response| response.status() == 200));
+        assert_eq!(upstream.requests(), 1);
+
+        // Without a coalescing window, later requests synthesize again.
+        send_request(&state, "/speak?text=Hello&volume=0.75").await;
+        assert_eq!(upstream.requests(), 2);
+
+        let state = test_state(&["--coalesce-window-ms", "10000"], &upstream);
+        send_request(&state, "/speak?text=Hello").await;
+        send_request(&state, "/speak?text=Hello&volume=0.75").await;
+        assert_eq!(upstream.requests(), 3);
+    }
+
+    #[actix_web::test]
+    async fn perceptual_hash_header() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let hash = |response: &actix_web::dev::ServiceResponse| {
+            assert_eq!(response.status(), 200);
+            let hash = response.headers().get("x-audio-perceptual-hash")?;
+            Some(hash.to_str().unwrap().to_string())
+        };
+        for args in [
+            &["--perceptual-hash"][..],
+            &["--perceptual-hash", "--serve-time-gain-format", "mp3"],
+        ] {
+            let state = test_state(args, &upstream);
+            let first = hash(&send_request(&state, "/speak?text=Hello").await).unwrap();
+            assert_eq!(first.len(), 16);
+            let key = resolve_query(&state.args, "text=Hello").cache_key;
+            let key = storage_cache_key(&state.args, &key);
+            let cached = state.shared.lock().speech_cache.get(&key).cloned().unwrap();
+            assert_eq!(cached.perceptual_hash, u64::from_str_radix(&first, 16).ok());
+            // The hash is computed before the volume is applied.
+            let quiet = hash(&send_request(&state, "/speak?text=Hello&volume=0.5").await);
+            assert_eq!(quiet, Some(first), "{:?}", args);
+        }
+
+        let state = test_state(&[], &upstream);
+        let response = send_request(&state, "/speak?text=Hello").await;
+        assert_eq!(hash(&response), None);
+    }
+
+    #[actix_web::test]
+    async fn low_output_sample_rates_accept_high_bitrates() {
+        let upstream = start_mock_upstream(std::time::Duration::ZERO);
+        let state = test_state(&["--output-sample-rate", "8000"], &upstream);
+        let response = send_request(&state, "/speak?text=Hello&bitrate=320").await;
+        assert_eq!(response.status(), 200);
+        let body = actix_web::test::read_body(response).await;
+        assert_eq!(audio::decode(body).unwrap().sample_rate, 8000);
+        let key = resolve_query(&state.args, "text=Hello&bitrate=320").cache_key;
+        assert!(state.shared.lock().speech_cache.contains(&key));
+    }
 }
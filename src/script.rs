@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+/// A Rhai script that rewrites the text of every request before it is synthesized, e.g. to
+/// apply deployment specific substitutions. The script gets the text in the `text` variable
+/// and has to evaluate to the new text.
+#[derive(Clone)]
+pub struct TextScript {
+    engine: Arc<rhai::Engine>,
+    ast: Arc<rhai::AST>,
+}
+
+impl TextScript {
+    /// Limits the work a script can do per request, so that it cannot stall the server.
+    const MAX_OPERATIONS: u64 = 100_000;
+
+    pub fn load(path: &std::path::Path) -> Result<Self, String> {
+        let mut engine = rhai::Engine::new();
+        engine.set_max_operations(Self::MAX_OPERATIONS);
+        engine.set_max_string_size(64 * 1024);
+        let ast = engine
+            .compile_file(path.into())
+            .map_err(|err| err.to_string())?;
+        Ok(Self {
+            engine: Arc::new(engine),
+            ast: Arc::new(ast),
+        })
+    }
+
+    pub fn transform(&self, text: &str) -> Result<String, Box<rhai::EvalAltResult>> {
+        let mut scope = rhai::Scope::new();
+        scope.push("text", text.to_string());
+        self.engine.eval_ast_with_scope(&mut scope, &self.ast)
+    }
+}
+
+impl std::fmt::Debug for TextScript {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TextScript")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_script(name: &str, source: &str) -> Result<TextScript, String> {
+        let path =
+            std::env::temp_dir().join(format!("speech-cache-{}-{}.rhai", name, std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let script = TextScript::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        script
+    }
+
+    #[test]
+    fn transforms_the_text() {
+        let script = load_script("replace", r#"text.replace("Dr.", "Doctor"); text"#).unwrap();
+        assert_eq!(script.transform("Dr. Who").unwrap(), "Doctor Who");
+    }
+
+    #[test]
+    fn limits_the_work_per_request() {
+        let script = load_script("loop", "loop {}").unwrap();
+        assert!(script.transform("Hello").is_err());
+    }
+
+    #[test]
+    fn rejects_scripts_without_text_result() {
+        let script = load_script("number", "42").unwrap();
+        assert!(script.transform("Hello").is_err());
+        assert!(load_script("syntax", "text +").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use hmac::{Hmac, Mac};
+use std::collections::HashMap;
+
+#[derive(derive_more::Display, derive_more::Error, Debug)]
+pub enum SignatureError {
+    #[display("request is not signed")]
+    Missing,
+    #[display("signature is invalid")]
+    Invalid,
+    #[display("signature has expired")]
+    Expired,
+    #[display("request was already used")]
+    Replayed,
+}
+
+#[derive(serde::Deserialize)]
+struct SignatureParams {
+    timestamp: Option<u64>,
+    nonce: Option<String>,
+    signature: Option<String>,
+}
+
+/// Checks that the query string carries a valid HMAC-SHA256 `signature` (hex encoded) of the
+/// query string without the signature parameter. The signed `timestamp` (Unix seconds) has to
+/// be recent and the signed `nonce` must not have been used while the signature is valid.
+pub fn verify(
+    key: &[u8],
+    query_string: &str,
+    now: u64,
+    max_age: u64,
+    used_nonces: &mut HashMap<String, u64>,
+) -> Result<(), SignatureError> {
+    const MAX_NONCE_LENGTH: usize = 64;
+
+    let params = actix_web::web::Query::<SignatureParams>::from_query(query_string)
+        .map_err(|_| SignatureError::Invalid)?
+        .into_inner();
+    let (Some(timestamp), Some(nonce), Some(signature)) =
+        (params.timestamp, params.nonce, params.signature)
+    else {
+        return Err(SignatureError::Missing);
+    };
+    if nonce.len() > MAX_NONCE_LENGTH {
+        return Err(SignatureError::Invalid);
+    }
+    if now.abs_diff(timestamp) > max_age {
+        return Err(SignatureError::Expired);
+    }
+
+    let signed_query = query_string
+        .split('&')
+        .filter(|pair| !pair.starts_with("signature="))
+        .collect::<Vec<_>>()
+        .join("&");
+    let signature = decode_hex(&signature).ok_or(SignatureError::Invalid)?;
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(signed_query.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| SignatureError::Invalid)?;
+
+    // Timestamps in the future keep the signature valid for longer than `max_age` from now.
+    used_nonces.retain(|_, valid_until| *valid_until >= now);
+    if used_nonces
+        .insert(nonce, timestamp.max(now) + max_age)
+        .is_some()
+    {
+        return Err(SignatureError::Replayed);
+    }
+    Ok(())
+}
+
+/// Appends the signature that [`verify`] expects, like clients do.
+#[cfg(test)]
+pub fn sign_query(key: &[u8], query_string: &str) -> String {
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(query_string.as_bytes());
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("{}&signature={}", query_string, signature)
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"secret";
+
+    fn sign(query_string: &str) -> String {
+        sign_query(KEY, query_string)
+    }
+
+    #[test]
+    fn accepts_valid_signatures_once() {
+        let mut used_nonces = HashMap::new();
+        let query = sign("text=Hello&timestamp=1000&nonce=abc");
+        assert!(verify(KEY, &query, 1010, 300, &mut used_nonces).is_ok());
+        assert!(matches!(
+            verify(KEY, &query, 1020, 300, &mut used_nonces),
+            Err(SignatureError::Replayed)
+        ));
+        let other_nonce = sign("text=Hello&timestamp=1000&nonce=def");
+        assert!(verify(KEY, &other_nonce, 1020, 300, &mut used_nonces).is_ok());
+
+        let future = sign("text=Hello&timestamp=1300&nonce=ghi");
+        assert!(verify(KEY, &future, 1000, 300, &mut used_nonces).is_ok());
+        assert!(matches!(
+            verify(KEY, &future, 1301, 300, &mut used_nonces),
+            Err(SignatureError::Replayed)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_signatures() {
+        let mut used_nonces = HashMap::new();
+        let query = sign("text=Hello&timestamp=1000&nonce=abc");
+        let tampered = query.replace("Hello", "Goodbye");
+        let cases = [
+            (tampered.as_str(), "invalid"),
+            (
+                "text=Hello&timestamp=1000&nonce=abc&signature=zz",
+                "invalid",
+            ),
+            ("text=Hello&timestamp=1000&nonce=abc", "not signed"),
+            ("text=Hello", "not signed"),
+        ];
+        for (query, error) in cases {
+            let err = verify(KEY, query, 1000, 300, &mut used_nonces).unwrap_err();
+            assert!(err.to_string().contains(error), "{}: {}", query, err);
+        }
+        assert!(matches!(
+            verify(b"other key", &query, 1000, 300, &mut used_nonces),
+            Err(SignatureError::Invalid)
+        ));
+        assert!(matches!(
+            verify(KEY, &query, 2000, 300, &mut used_nonces),
+            Err(SignatureError::Expired)
+        ));
+        assert!(used_nonces.is_empty());
+    }
+}